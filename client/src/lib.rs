@@ -0,0 +1,358 @@
+//! A client for the voice search server's websocket protocol, for smoke tests
+//! and load tests.
+//!
+//! It sends a recording the way a browser does when the page calls
+//! `MediaRecorder.start(timeslice)`: a chunk of the webm file every
+//! `timeslice`, as fragments of a single websocket message, so the server
+//! sees the recording arrive at the speed it was spoken.
+//!
+//! ```no_run
+//! use voice_search_client::{Client, DEFAULT_URL, Pacing};
+//!
+//! # actix_rt::System::new().block_on(async {
+//! let recording = std::fs::read("test_data/english/complete_book_of_cheese_mono.webm")?;
+//! let mut client = Client::connect(DEFAULT_URL).await?;
+//! let transcription = client.stream_recording(&recording, Pacing::default()).await?;
+//! println!("{} ({:?} after the last chunk)", transcription.result.text, transcription.latency());
+//! # Ok::<(), anyhow::Error>(())
+//! # });
+//! ```
+
+use std::{io::Cursor, time::Duration};
+
+use actix_http::ws::Item;
+use actix_rt::time::Instant;
+use anyhow::{Result, anyhow, bail};
+use awc::{
+    BoxedSocket,
+    ws::{Codec, Frame, Message},
+};
+use futures_util::{SinkExt as _, StreamExt as _};
+use matroska_demuxer::{Frame as MatroskaFrame, MatroskaFile};
+
+/// Where `cargo run` serves the websocket endpoint
+pub const DEFAULT_URL: &str = "ws://127.0.0.1:7025/";
+
+// If we can't tell how long a recording is, assume that it was encoded at
+// about the bitrate of the recordings in test_data.
+const FALLBACK_BITS_PER_SECOND: usize = 64_000;
+
+/// How to send a recording to the server
+#[derive(Debug, Clone, Copy)]
+pub struct Pacing {
+    /// How much of the recording goes in each chunk, like the `timeslice`
+    /// argument to `MediaRecorder.start()`
+    pub timeslice: Duration,
+    /// Wait a timeslice before sending each chunk, like a browser that is
+    /// still recording.  Otherwise, send the chunks as fast as possible.
+    pub realtime: bool,
+}
+
+impl Default for Pacing {
+    fn default() -> Self {
+        Pacing {
+            timeslice: Duration::from_millis(250),
+            realtime: true,
+        }
+    }
+}
+
+/// A text message from the server
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reply {
+    pub text: String,
+    /// How long after we started recording the reply arrived
+    pub elapsed: Duration,
+}
+
+impl Reply {
+    /// The server sends errors as `{"error": "..."}`
+    pub fn error(&self) -> Option<String> {
+        let json: serde_json::Value = serde_json::from_str(&self.text).ok()?;
+        json.get("error")?.as_str().map(str::to_owned)
+    }
+
+    /// Why the server couldn't transcribe the recording, e.g. `"no_speech"`
+    /// from `{"outcome": "no_speech"}`.  None if it was recognized.
+    pub fn unrecognized(&self) -> Option<String> {
+        let json: serde_json::Value = serde_json::from_str(&self.text).ok()?;
+        match json.get("outcome")?.as_str()? {
+            "recognized" => None,
+            outcome => Some(outcome.to_owned()),
+        }
+    }
+}
+
+/// Everything the server sent back for a recording, and when
+#[derive(Debug, Clone)]
+pub struct Transcription {
+    /// Replies that arrived while we were still sending the recording
+    pub interim: Vec<Reply>,
+    /// The first reply after we sent the whole recording
+    pub result: Reply,
+    /// How many chunks we sent the recording in
+    pub chunks: usize,
+    /// When we sent the last chunk
+    pub upload: Duration,
+}
+
+impl Transcription {
+    /// How long the patron would wait for their transcription after they
+    /// stopped recording
+    pub fn latency(&self) -> Duration {
+        self.result.elapsed.saturating_sub(self.upload)
+    }
+}
+
+/// A websocket connection to the voice search server.  Session settings last
+/// as long as the connection.
+pub struct Client {
+    socket: actix_codec::Framed<BoxedSocket, Codec>,
+}
+
+impl Client {
+    /// Connect to the server, e.g. at [`DEFAULT_URL`].  This must be called
+    /// within an actix (or tokio current thread) runtime.
+    pub async fn connect(url: &str) -> Result<Client> {
+        let (_, socket) = awc::Client::new()
+            .ws(url)
+            .max_frame_size(2_usize.pow(20))
+            .connect()
+            .await
+            .map_err(|err| anyhow!("Could not connect to {url}: {err}"))?;
+        Ok(Client { socket })
+    }
+
+    /// Send a JSON control message, e.g. `{"session": {"response_format": "json"}}`.
+    /// The server only replies if the settings are invalid, and that reply
+    /// arrives along with the next transcription.
+    pub async fn send_settings(&mut self, settings: &str) -> Result<()> {
+        self.send(Message::Text(settings.into())).await
+    }
+
+    /// Send a webm recording in chunks, and wait for its transcription
+    pub async fn stream_recording(
+        &mut self,
+        recording: &[u8],
+        pacing: Pacing,
+    ) -> Result<Transcription> {
+        let chunks = chunks(recording, pacing.timeslice);
+        let started = Instant::now();
+        let mut interim = vec![];
+        for (i, chunk) in chunks.iter().enumerate() {
+            if pacing.realtime {
+                let due = started + pacing.timeslice * (i as u32 + 1);
+                while let Ok(reply) = tokio::time::timeout_at(due, self.receive(started)).await {
+                    interim.push(reply?);
+                }
+            }
+            let chunk = chunk.to_vec().into();
+            let message = match (i, chunks.len()) {
+                (_, 1) => Message::Binary(chunk),
+                (0, _) => Message::Continuation(Item::FirstBinary(chunk)),
+                (i, len) if i == len - 1 => Message::Continuation(Item::Last(chunk)),
+                _ => Message::Continuation(Item::Continue(chunk)),
+            };
+            self.send(message).await?;
+        }
+        let upload = started.elapsed();
+        let result = self.receive(started).await?;
+        Ok(Transcription {
+            interim,
+            result,
+            chunks: chunks.len(),
+            upload,
+        })
+    }
+
+    async fn send(&mut self, message: Message) -> Result<()> {
+        self.socket
+            .send(message)
+            .await
+            .map_err(|err| anyhow!("Could not send to the server: {err}"))
+    }
+
+    // Wait for the next text message, answering pings along the way
+    async fn receive(&mut self, started: Instant) -> Result<Reply> {
+        loop {
+            let frame = match self.socket.next().await {
+                Some(frame) => {
+                    frame.map_err(|err| anyhow!("Bad message from the server: {err}"))?
+                }
+                None => bail!("The server closed the connection"),
+            };
+            match frame {
+                Frame::Text(text) => {
+                    return Ok(Reply {
+                        text: String::from_utf8_lossy(&text).into_owned(),
+                        elapsed: started.elapsed(),
+                    });
+                }
+                Frame::Ping(payload) => self.send(Message::Pong(payload)).await?,
+                Frame::Close(reason) => bail!("The server closed the connection: {reason:?}"),
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Split a recording into the chunks that MediaRecorder would have produced
+/// every `timeslice`.  MediaRecorder splits at block boundaries rather than
+/// at exact byte offsets, but the server only looks at the whole recording,
+/// so an even split by size is close enough.
+pub fn chunks(recording: &[u8], timeslice: Duration) -> Vec<&[u8]> {
+    let chunk_size = match recording_duration(recording) {
+        Some(duration) if !duration.is_zero() => (recording.len() as f64 * timeslice.as_secs_f64()
+            / duration.as_secs_f64())
+        .ceil() as usize,
+        _ => (FALLBACK_BITS_PER_SECOND / 8) * timeslice.as_millis() as usize / 1000,
+    };
+    recording.chunks(chunk_size.max(1)).collect()
+}
+
+/// How long a webm recording is, from the timestamp of its last frame
+pub fn recording_duration(recording: &[u8]) -> Option<Duration> {
+    let mut file = MatroskaFile::open(Cursor::new(recording)).ok()?;
+    let timestamp_scale = file.info().timestamp_scale().get();
+    let mut frame = MatroskaFrame::default();
+    let mut last_timestamp = None;
+    while let Ok(true) = file.next_frame(&mut frame) {
+        last_timestamp = Some(frame.timestamp);
+    }
+    Some(Duration::from_nanos(last_timestamp? * timestamp_scale))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{App, HttpRequest, HttpResponse, rt, web};
+    use actix_ws::Message as ServerMessage;
+
+    use super::*;
+
+    const RECORDING: &str = "../test_data/english/complete_book_of_cheese_mono.webm";
+
+    // Replies to each fragment with how many bytes it has received so far, and
+    // to each complete recording with "done"
+    async fn counting_server(
+        req: HttpRequest,
+        stream: web::Payload,
+    ) -> Result<HttpResponse, actix_web::Error> {
+        let (res, mut session, mut stream) = actix_ws::handle(&req, stream)?;
+        rt::spawn(async move {
+            let mut received = 0;
+            while let Some(Ok(msg)) = stream.next().await {
+                match msg {
+                    ServerMessage::Continuation(item) => match item {
+                        actix_ws::Item::FirstBinary(bytes) | actix_ws::Item::Continue(bytes) => {
+                            received += bytes.len();
+                            session.text(received.to_string()).await.unwrap();
+                        }
+                        actix_ws::Item::Last(bytes) => {
+                            received += bytes.len();
+                            session.text(format!("done {received}")).await.unwrap();
+                            received = 0;
+                        }
+                        _ => {}
+                    },
+                    ServerMessage::Binary(bytes) => {
+                        session.text(format!("done {}", bytes.len())).await.unwrap();
+                    }
+                    ServerMessage::Text(text) => session.text(text).await.unwrap(),
+                    _ => {}
+                }
+            }
+        });
+        Ok(res)
+    }
+
+    fn start_server() -> actix_test::TestServer {
+        actix_test::start(|| App::new().route("/", web::get().to(counting_server)))
+    }
+
+    #[test]
+    fn it_splits_a_recording_into_a_chunk_per_timeslice() {
+        let recording = std::fs::read(RECORDING).unwrap();
+        let duration = recording_duration(&recording).unwrap();
+        assert!(duration > Duration::from_secs(4) && duration < Duration::from_secs(6));
+        let chunks = chunks(&recording, Duration::from_millis(250));
+        assert_eq!(chunks.len(), (duration.as_millis() as usize).div_ceil(250));
+        assert_eq!(chunks.concat(), recording);
+    }
+
+    #[test]
+    fn it_guesses_the_chunk_size_of_recordings_it_cannot_demux() {
+        let recording = vec![0; 20_000];
+        assert_eq!(recording_duration(&recording), None);
+        assert_eq!(chunks(&recording, Duration::from_millis(500)).len(), 5);
+    }
+
+    #[test]
+    fn it_recognizes_error_replies() {
+        let reply = |text: &str| Reply {
+            text: text.to_owned(),
+            elapsed: Duration::ZERO,
+        };
+        assert_eq!(
+            reply(r#"{"error": "max_tokens must be at least 1"}"#).error(),
+            Some("max_tokens must be at least 1".to_owned())
+        );
+        assert_eq!(reply(" The Complete Book of Cheese").error(), None);
+        assert_eq!(reply(r#"{"text": " The"}"#).error(), None);
+    }
+
+    #[test]
+    fn it_recognizes_replies_that_were_not_transcribed() {
+        let reply = |text: &str| Reply {
+            text: text.to_owned(),
+            elapsed: Duration::ZERO,
+        };
+        assert_eq!(
+            reply(r#"{"outcome": "too_noisy"}"#).unrecognized(),
+            Some("too_noisy".to_owned())
+        );
+        assert_eq!(
+            reply(r#"{"text": " The", "outcome": "recognized"}"#).unrecognized(),
+            None
+        );
+        assert_eq!(reply(" The Complete Book of Cheese").unrecognized(), None);
+        assert_eq!(reply(r#"{"error": "Unsupported"}"#).unrecognized(), None);
+    }
+
+    #[actix_web::test]
+    async fn it_streams_a_recording_as_one_fragmented_message() {
+        let server = start_server();
+        let recording = std::fs::read(RECORDING).unwrap();
+        let mut client = Client::connect(&server.url("/").replace("http", "ws"))
+            .await
+            .unwrap();
+        let pacing = Pacing {
+            timeslice: Duration::from_millis(50),
+            realtime: true,
+        };
+        let transcription = client.stream_recording(&recording, pacing).await.unwrap();
+        assert!(transcription.chunks > 1);
+        assert_eq!(transcription.interim.len(), transcription.chunks - 1);
+        assert_eq!(
+            transcription.result.text,
+            format!("done {}", recording.len())
+        );
+        assert!(transcription.upload >= Duration::from_millis(50) * transcription.chunks as u32);
+    }
+
+    #[actix_web::test]
+    async fn it_can_send_everything_at_once() {
+        let server = start_server();
+        let mut client = Client::connect(&server.url("/").replace("http", "ws"))
+            .await
+            .unwrap();
+        client.send_settings(r#"{"session": {}}"#).await.unwrap();
+        let pacing = Pacing {
+            timeslice: Duration::from_secs(60),
+            realtime: false,
+        };
+        let transcription = client.stream_recording(b"webm", pacing).await.unwrap();
+        assert_eq!(transcription.chunks, 1);
+        assert!(transcription.interim.is_empty());
+        assert_eq!(transcription.result.text, r#"{"session": {}}"#);
+    }
+}
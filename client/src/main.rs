@@ -0,0 +1,100 @@
+// A command line client for smoke tests and load tests of the voice search server:
+//
+//   cargo run -p voice_search_client -- test_data/english/*.webm
+//   cargo run -p voice_search_client -- recording.webm --settings '{"session": {"response_format": "json"}}' --repeat 10
+//
+// For each recording, it prints any replies that arrived while it was still
+// sending, the final reply, and how long everything took.  It fails if the
+// server responded with any errors, or couldn't transcribe any of the recordings.
+
+use std::{path::PathBuf, time::Duration};
+
+use anyhow::{Context, Result, bail};
+use clap::Parser;
+use voice_search_client::{Client, DEFAULT_URL, Pacing, Reply};
+
+#[derive(Parser)]
+#[command(about = "Stream webm recordings to the voice search server")]
+struct Cli {
+    /// The webm recordings to send
+    #[arg(required = true)]
+    files: Vec<PathBuf>,
+    /// The server's websocket endpoint
+    #[arg(long, default_value = DEFAULT_URL)]
+    url: String,
+    /// How many milliseconds of the recording to send in each chunk
+    #[arg(long, default_value_t = 250)]
+    timeslice_ms: u64,
+    /// Send each recording as fast as possible, rather than as it would be recorded
+    #[arg(long)]
+    no_pacing: bool,
+    /// A JSON control message to send before the recordings
+    #[arg(long)]
+    settings: Option<String>,
+    /// How many times to send each recording
+    #[arg(long, default_value_t = 1)]
+    repeat: usize,
+}
+
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+    actix_rt::System::new().block_on(run(cli))
+}
+
+async fn run(cli: Cli) -> Result<()> {
+    let pacing = Pacing {
+        timeslice: Duration::from_millis(cli.timeslice_ms),
+        realtime: !cli.no_pacing,
+    };
+    let mut client = Client::connect(&cli.url).await?;
+    if let Some(settings) = &cli.settings {
+        client.send_settings(settings).await?;
+    }
+    let mut errors = 0;
+    let mut unrecognized = 0;
+    for path in &cli.files {
+        let recording = std::fs::read(path).with_context(|| format!("Could not read {path:?}"))?;
+        for _ in 0..cli.repeat {
+            let transcription = client.stream_recording(&recording, pacing).await?;
+            println!("==> {} <==", path.display());
+            for reply in &transcription.interim {
+                print_reply("interim", reply);
+            }
+            print_reply("final", &transcription.result);
+            println!(
+                "{} chunks, upload {:.2}s, latency {:.2}s, total {:.2}s\n",
+                transcription.chunks,
+                transcription.upload.as_secs_f64(),
+                transcription.latency().as_secs_f64(),
+                transcription.result.elapsed.as_secs_f64()
+            );
+            errors += transcription
+                .interim
+                .iter()
+                .chain([&transcription.result])
+                .filter(|reply| reply.error().is_some())
+                .count();
+            if transcription.result.unrecognized().is_some() {
+                unrecognized += 1;
+            }
+        }
+    }
+    match (errors, unrecognized) {
+        (0, 0) => {}
+        (0, _) => bail!("The server could not transcribe {unrecognized} recording(s)"),
+        (_, 0) => bail!("The server responded with {errors} error(s)"),
+        _ => bail!(
+            "The server responded with {errors} error(s), and could not transcribe \
+             {unrecognized} recording(s)"
+        ),
+    }
+    Ok(())
+}
+
+fn print_reply(kind: &str, reply: &Reply) {
+    println!(
+        "[{:>6.2}s] {kind}: {}",
+        reply.elapsed.as_secs_f64(),
+        reply.text.trim()
+    );
+}
@@ -2,87 +2,806 @@
 // a vector of PCM samples (see https://en.wikipedia.org/wiki/Pulse-code_modulation)
 //
 // Webm uses a Matroska (aka MKV) format and a Vorbis or OPUS encoding.
-// This code supports only the OPUS encoding, since that is the encoding
-// that Firefox and Chromium use.
+// Firefox and Chromium use OPUS, while older recorders used Vorbis, which
+// the vorbis module decodes.  Opus in an Ogg container (which Firefox
+// can also record) is handled by the ogg module, and AAC in an MP4 container
+// (which Safari records) by the mp4 module.  WAV, FLAC and MP3 files that
+// staff export from Audacity are handled by the lab module.  The registry
+// module chooses between them by the first few bytes of the file, or by the
+// MIME type that the client told us.
 //
 // Each sample is expressed in a 32 bit float
-// This can handle Mono or Stereo, which is kinda cool!
+// This can handle Mono or Stereo, which is kinda cool!  Whisper only
+// hears mono, though, so stereo recordings are turned into a single
+// channel according to the client's ChannelSelection.
+//
+// Opus decodes at 48 kHz, which we then resample to the rate that whisper
+// expects with a windowed sinc filter (see `resample`).  The timeline module
+// puts each decoded Opus packet at the time its webm block says, so that gaps
+// in a recording stay gaps.
+//
+// Clients that capture audio with an AudioWorklet can skip the containers
+// entirely, and send raw PCM frames in a format that they declare up front
+// (see `RawPcm`).  Those go straight to `to_mono` and `resample`.
+
+mod container;
+mod lab;
+mod mp4;
+mod ogg;
+mod registry;
+mod timeline;
+mod vorbis;
+
+pub use registry::{AudioDecoder, DecoderRegistry, UnsupportedFormat};
 
-use std::io::{Read, Seek};
+use std::{
+    f64::consts::PI,
+    io::{Cursor, Read, Seek},
+    str::FromStr,
+};
 
-use anyhow::{Result, anyhow};
+use anyhow::{Result, anyhow, bail};
 use matroska_demuxer::{Frame, MatroskaFile};
 use opus::{Channels, Decoder};
+use serde::{Deserialize, Serialize};
 
 use crate::config;
 
 struct Track<R: Seek + Read> {
     sample_rate: f64,
-    channels: Channels,
+    channels: usize,
+    codec: Codec,
     track: u64,
+    // Nanoseconds per block timestamp tick, and how many nanoseconds of each
+    // block's timestamp are the codec's delay rather than the recording
+    timestamp_scale: u64,
+    codec_delay: u64,
     reader: MatroskaFile<R>,
 }
 
+// The encodings that we can decode out of a webm file
+enum Codec {
+    // The samples (at 48 kHz) of the encoder's warm up, from the OpusHead in CodecPrivate
+    Opus { pre_skip: usize },
+    // Vorbis needs the header packets from the track's CodecPrivate
+    Vorbis(Vec<u8>),
+}
+
+enum TrackDecoder {
+    Opus(OpusStream),
+    Vorbis(Box<vorbis::Vorbis>),
+}
+
 impl<R: Seek + Read> Track<R> {
-    pub fn decode(&mut self) -> Result<(Vec<f32>, f64)> {
-        let mut decoder = Decoder::new(config::AUDIO_DECODE_SAMPLE_RATE, self.channels).unwrap();
+    fn decode(&mut self) -> Result<DecodedAudio> {
+        // Opus always decodes at the rate we ask for, Vorbis at the rate it was
+        // recorded at, according to its own headers
+        let (mut decoder, sample_rate) = match &self.codec {
+            Codec::Opus { .. } => {
+                let channels = if self.channels == 1 {
+                    Channels::Mono
+                } else {
+                    Channels::Stereo
+                };
+                (
+                    TrackDecoder::Opus(OpusStream::new(channels)?),
+                    config::OPUS_DECODE_SAMPLE_RATE,
+                )
+            }
+            Codec::Vorbis(codec_private) => {
+                let decoder = vorbis::Vorbis::new(codec_private)?;
+                let sample_rate = decoder.sample_rate;
+                (TrackDecoder::Vorbis(Box::new(decoder)), sample_rate)
+            }
+        };
+
+        let pre_skip = match self.codec {
+            Codec::Opus { pre_skip } => {
+                pre_skip * sample_rate as usize / config::OPUS_DECODE_SAMPLE_RATE as usize
+            }
+            Codec::Vorbis(_) => 0,
+        };
+        let channels = match &decoder {
+            TrackDecoder::Opus(decoder) => decoder.channels as usize,
+            TrackDecoder::Vorbis(decoder) => decoder.channels,
+        };
+        let mut timeline = timeline::Timeline::new(
+            channels,
+            sample_rate,
+            self.timestamp_scale,
+            self.codec_delay,
+            pre_skip,
+        );
 
         let mut pcm_data = Vec::new();
+        let mut decoded = Vec::new();
+        let mut dropped_packets = 0;
+        let mut last_timestamp = None;
         let mut packet = Frame::default();
-        while self.reader.next_frame(&mut packet).unwrap() {
+        loop {
+            match self.reader.next_frame(&mut packet) {
+                Ok(true) => {}
+                Ok(false) => break,
+                // e.g. the browser tab was closed in the middle of an upload,
+                // so we keep what we decoded up to there
+                Err(err) => {
+                    log::warn!("Stopped decoding a truncated or damaged webm file: {err}");
+                    break;
+                }
+            }
             if packet.track != self.track {
                 continue;
             }
             if packet.is_invisible {
                 continue;
             }
-            let num_samples = decoder.get_nb_samples(&packet.data)?;
-            // The Opus decoder needs a vector to put all its values in
-            let mut decoded = vec![0.0; num_samples * self.channels as usize];
-            let _ = decoder.decode_float(&packet.data, &mut decoded, false);
-            pcm_data.append(&mut decoded);
+            // Frames laced into one block share its timestamp
+            let mut timestamp = Some(packet.timestamp).filter(|t| Some(*t) != last_timestamp);
+            last_timestamp = Some(packet.timestamp);
+            decoded.clear();
+            match &mut decoder {
+                TrackDecoder::Opus(decoder) => {
+                    // Concealed packets fill the gap since the last block we placed
+                    if decoder.decode_packet(&packet.data, &mut decoded) > 0 {
+                        timestamp = None;
+                    }
+                }
+                TrackDecoder::Vorbis(decoder) => {
+                    // A Vorbis packet's samples come out half a block after it
+                    // (they overlap with the next packet's), so its timestamp
+                    // doesn't say where they go, and we keep them back to back
+                    timestamp = None;
+                    // Vorbis has no concealment, so we skip the packet
+                    if decoder.decode_packet(&packet.data, &mut decoded).is_err() {
+                        dropped_packets += 1;
+                    }
+                }
+            }
+            // A dropped packet leaves a gap for the next one to fill
+            if !decoded.is_empty() {
+                timeline.place(timestamp, &decoded, &mut pcm_data);
+                check_length(pcm_data.len(), channels, sample_rate)?;
+            }
+        }
+
+        if let TrackDecoder::Opus(decoder) = decoder {
+            dropped_packets += decoder.finish();
+        }
+        Ok(DecodedAudio {
+            interleaved: pcm_data,
+            channels,
+            sample_rate,
+            original_sample_rate: match self.codec {
+                Codec::Opus { .. } => self.sample_rate,
+                Codec::Vorbis(_) => sample_rate as f64,
+            },
+            dropped_packets,
+        })
+    }
+}
+
+/// Samples straight out of an [`AudioDecoder`], before we turn them into what whisper expects
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecodedAudio {
+    /// The samples of each channel, interleaved, e.g. left, right, left, right...
+    pub interleaved: Vec<f32>,
+    pub channels: usize,
+    /// The rate that the decoder produced the samples at
+    pub sample_rate: u32,
+    /// The rate that the recording was made at, according to the file
+    pub original_sample_rate: f64,
+    /// How many damaged packets the decoder skipped or concealed
+    pub dropped_packets: usize,
+}
+
+// Decoders call this as the samples pile up, and stop with the error once a
+// recording decodes to more than config::MAX_RECORDING_SECONDS of audio
+fn check_length(interleaved: usize, channels: usize, sample_rate: u32) -> Result<()> {
+    if interleaved > config::MAX_RECORDING_SECONDS * sample_rate as usize * channels {
+        bail!(
+            "Recordings can be at most {} seconds long",
+            config::MAX_RECORDING_SECONDS
+        );
+    }
+    Ok(())
+}
+
+// Webm is a kind of Matroska file, which starts with this EBML header id
+const MATROSKA_MAGIC: &[u8] = &[0x1a, 0x45, 0xdf, 0xa3];
+
+struct MatroskaDecoder;
+
+impl AudioDecoder for MatroskaDecoder {
+    fn name(&self) -> &str {
+        "Matroska"
+    }
+
+    fn sniff(&self, recording: &[u8]) -> bool {
+        recording.starts_with(MATROSKA_MAGIC)
+    }
+
+    fn mime_types(&self) -> &[&str] {
+        &[
+            "audio/webm",
+            "video/webm",
+            "audio/x-matroska",
+            "video/x-matroska",
+        ]
+    }
+
+    fn decode(&self, recording: &[u8]) -> Result<DecodedAudio> {
+        demux(Cursor::new(recording))?.decode()
+    }
+}
+
+// Decodes the Opus packets of a track, whichever container they came from.
+// A packet that the decoder rejects is dropped, and the gap is filled in when
+// the next good packet arrives: the last lost packet from the forward error
+// correction data in the good one (if the encoder added any), and any before it
+// with packet loss concealment.  So a corrupt packet costs us a few
+// milliseconds of guessed audio rather than the whole recording.
+struct OpusStream {
+    decoder: Decoder,
+    channels: Channels,
+    // The number of samples (per channel) in the last good packet, which is
+    // how much audio to conceal for each lost one
+    frame_size: usize,
+    // Packets lost since the last good one
+    lost: usize,
+    dropped_packets: usize,
+}
+
+impl OpusStream {
+    fn new(channels: Channels) -> Result<OpusStream> {
+        Ok(OpusStream {
+            decoder: Decoder::new(config::OPUS_DECODE_SAMPLE_RATE, channels)?,
+            channels,
+            // 20 ms, which is what browsers record
+            frame_size: config::OPUS_DECODE_SAMPLE_RATE as usize / 50,
+            lost: 0,
+            dropped_packets: 0,
+        })
+    }
+
+    // Decode a packet onto the end of pcm_data, with the channels interleaved.
+    // Returns how many lost packets were filled in before it.
+    fn decode_packet(&mut self, packet: &[u8], pcm_data: &mut Vec<f32>) -> usize {
+        let Ok(frame_size) = self.decoder.get_nb_samples(packet) else {
+            self.drop_packet();
+            return 0;
+        };
+        let filled_in = self.lost;
+        if self.lost > 0 {
+            self.conceal(self.lost - 1, pcm_data);
+            if self
+                .decode_into(packet, frame_size, true, pcm_data)
+                .is_err()
+            {
+                self.conceal(1, pcm_data);
+            }
+            self.lost = 0;
+        }
+        match self.decode_into(packet, frame_size, false, pcm_data) {
+            Ok(()) => self.frame_size = frame_size,
+            Err(_) => self.drop_packet(),
+        }
+        filled_in
+    }
+
+    // How many packets we dropped.  Lost packets at the very end aren't
+    // concealed, since there's nothing after them to lead into.
+    fn finish(self) -> usize {
+        if self.lost > 0 {
+            log::warn!("Dropped the last {} Opus packets of a recording", self.lost);
+        }
+        self.dropped_packets
+    }
+
+    fn drop_packet(&mut self) {
+        self.lost += 1;
+        self.dropped_packets += 1;
+    }
+
+    fn decode_into(
+        &mut self,
+        packet: &[u8],
+        frame_size: usize,
+        fec: bool,
+        pcm_data: &mut Vec<f32>,
+    ) -> Result<()> {
+        // The Opus decoder needs a vector to put all its values in
+        let mut decoded = vec![0.0; frame_size * self.channels as usize];
+        let decoded_samples = self.decoder.decode_float(packet, &mut decoded, fec)?;
+        decoded.truncate(decoded_samples * self.channels as usize);
+        pcm_data.append(&mut decoded);
+        Ok(())
+    }
+
+    // Guess at the audio of lost packets from what came before them
+    fn conceal(&mut self, packets: usize, pcm_data: &mut Vec<f32>) {
+        for _ in 0..packets {
+            let frame_size = self.frame_size;
+            if self.decode_into(&[], frame_size, false, pcm_data).is_err() {
+                // Silence is better than nothing, as it keeps the timing right
+                pcm_data.resize(pcm_data.len() + frame_size * self.channels as usize, 0.0);
+            }
         }
+    }
+}
+
+/// How to turn a recording with several channels into the single channel
+/// that whisper listens to
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(try_from = "ChannelSetting", into = "ChannelSetting")]
+pub enum ChannelSelection {
+    /// Average the channels
+    #[default]
+    Downmix,
+    /// Use the channel with the most energy, e.g. when the patron is
+    /// closer to one of two microphones
+    Loudest,
+    /// Use just this channel, counting from 0 (the left channel)
+    Channel(usize),
+}
+
+// Clients choose a ChannelSelection with "downmix", "loudest", or a channel number
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+enum ChannelSetting {
+    Number(usize),
+    Name(String),
+}
+
+impl TryFrom<ChannelSetting> for ChannelSelection {
+    type Error = anyhow::Error;
+
+    fn try_from(setting: ChannelSetting) -> Result<Self> {
+        match setting {
+            ChannelSetting::Number(channel) => Ok(ChannelSelection::Channel(channel)),
+            ChannelSetting::Name(name) => name.parse(),
+        }
+    }
+}
+
+impl From<ChannelSelection> for ChannelSetting {
+    fn from(selection: ChannelSelection) -> Self {
+        match selection {
+            ChannelSelection::Downmix => ChannelSetting::Name("downmix".to_owned()),
+            ChannelSelection::Loudest => ChannelSetting::Name("loudest".to_owned()),
+            ChannelSelection::Channel(channel) => ChannelSetting::Number(channel),
+        }
+    }
+}
+
+impl FromStr for ChannelSelection {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "downmix" => Ok(ChannelSelection::Downmix),
+            "loudest" => Ok(ChannelSelection::Loudest),
+            _ => s.parse().map(ChannelSelection::Channel).map_err(|_| {
+                anyhow!(
+                    "channel must be \"downmix\", \"loudest\", or a channel number, but got {s:?}"
+                )
+            }),
+        }
+    }
+}
+
+// Decode a recording with one of the registry's decoders into mono samples at
+// config::AUDIO_DECODE_SAMPLE_RATE.  The MIME type is a hint for recordings
+// whose first bytes we don't recognize.
+// Also returns how many damaged packets the decoder dropped.
+pub fn pcm_decode_with(
+    registry: &DecoderRegistry,
+    recording: &[u8],
+    mime_type: Option<&str>,
+    selection: ChannelSelection,
+) -> Result<(Vec<f32>, usize)> {
+    let decoded = registry.decode(recording, mime_type)?;
+    let dropped_packets = decoded.dropped_packets;
+    let (samples, _) = decoded_to_mono(decoded, selection, config::AUDIO_DECODE_SAMPLE_RATE)?;
+    Ok((samples, dropped_packets))
+}
+
+fn decoded_to_mono(
+    decoded: DecodedAudio,
+    selection: ChannelSelection,
+    sample_rate: u32,
+) -> Result<(Vec<f32>, f64)> {
+    // The container says what these are, and the container came from the client
+    if !(config::MIN_DECODED_SAMPLE_RATE..=config::MAX_DECODED_SAMPLE_RATE)
+        .contains(&decoded.sample_rate)
+    {
+        return Err(UnsupportedFormat::new(format!(
+            "a recording at {} Hz, which is not between {} and {} Hz",
+            decoded.sample_rate,
+            config::MIN_DECODED_SAMPLE_RATE,
+            config::MAX_DECODED_SAMPLE_RATE
+        ))
+        .into());
+    }
+    if !(1..=config::MAX_DECODED_CHANNELS).contains(&decoded.channels) {
+        return Err(UnsupportedFormat::new(format!(
+            "a recording with {} channels, which is not between 1 and {}",
+            decoded.channels,
+            config::MAX_DECODED_CHANNELS
+        ))
+        .into());
+    }
+    let samples = to_mono(&decoded.interleaved, decoded.channels, selection)?;
+    let samples = resample(&samples, decoded.sample_rate, sample_rate);
+    Ok((samples, decoded.original_sample_rate))
+}
+
+/// How a websocket client sends its audio
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "format", rename_all = "snake_case")]
+pub enum Input {
+    /// A whole recording (webm, Ogg, MP4, WAV, FLAC or MP3) in each binary
+    /// message, optionally with a MIME type like MediaRecorder.mimeType
+    Recording {
+        #[serde(default)]
+        mime_type: Option<String>,
+    },
+    /// Raw PCM frames spread over as many binary messages as the client
+    /// likes, followed by an empty binary message to end the recording
+    Pcm(RawPcm),
+}
 
-        Ok((pcm_data, self.sample_rate))
+impl Default for Input {
+    fn default() -> Self {
+        Input::Recording { mime_type: None }
     }
 }
 
-pub fn pcm_decode<R: Seek + Read>(original: R) -> Result<(Vec<f32>, f64)> {
-    let mut track: Track<R> = demux(original)?;
-    track.decode()
+/// What a client's raw PCM frames contain.  The channels are interleaved.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RawPcm {
+    pub sample_rate: u32,
+    pub channels: usize,
+    pub sample_format: SampleFormat,
+}
+
+/// Little-endian sample formats, named the way ffmpeg names them
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SampleFormat {
+    /// 16 bit signed integers, e.g. from `Int16Array`
+    S16le,
+    /// 32 bit floats between -1 and 1, which is what an AudioWorklet gets
+    F32le,
+}
+
+impl SampleFormat {
+    fn bytes(self) -> usize {
+        match self {
+            SampleFormat::S16le => 2,
+            SampleFormat::F32le => 4,
+        }
+    }
+}
+
+impl RawPcm {
+    /// Explains what is wrong with the format, if anything
+    pub fn validate(&self) -> Result<()> {
+        if !(config::MIN_RAW_PCM_SAMPLE_RATE..=config::MAX_RAW_PCM_SAMPLE_RATE)
+            .contains(&self.sample_rate)
+        {
+            bail!(
+                "sample_rate must be between {} and {}, but got {}",
+                config::MIN_RAW_PCM_SAMPLE_RATE,
+                config::MAX_RAW_PCM_SAMPLE_RATE,
+                self.sample_rate
+            );
+        }
+        if !(1..=config::MAX_RAW_PCM_CHANNELS).contains(&self.channels) {
+            bail!(
+                "channels must be between 1 and {}, but got {}",
+                config::MAX_RAW_PCM_CHANNELS,
+                self.channels
+            );
+        }
+        Ok(())
+    }
+
+    /// How many bytes a frame (one sample from each channel) takes
+    pub fn frame_bytes(&self) -> usize {
+        self.channels * self.sample_format.bytes()
+    }
+
+    /// How many bytes this many seconds of audio takes
+    pub fn bytes_per_second(&self) -> usize {
+        self.sample_rate as usize * self.frame_bytes()
+    }
+}
+
+// Decode raw PCM frames into mono samples at config::AUDIO_DECODE_SAMPLE_RATE
+pub fn raw_pcm_decode(
+    bytes: &[u8],
+    format: &RawPcm,
+    selection: ChannelSelection,
+) -> Result<Vec<f32>> {
+    format.validate()?;
+    if !bytes.len().is_multiple_of(format.frame_bytes()) {
+        bail!(
+            "The raw PCM recording ends partway through a frame: {} bytes is not a multiple of {}",
+            bytes.len(),
+            format.frame_bytes()
+        );
+    }
+    let interleaved: Vec<f32> = match format.sample_format {
+        SampleFormat::S16le => bytes
+            .chunks_exact(2)
+            .map(|sample| i16::from_le_bytes([sample[0], sample[1]]) as f32 / 32768.0)
+            .collect(),
+        SampleFormat::F32le => bytes
+            .chunks_exact(4)
+            .map(|sample| f32::from_le_bytes([sample[0], sample[1], sample[2], sample[3]]))
+            .collect(),
+    };
+    let samples = to_mono(&interleaved, format.channels, selection)?;
+    Ok(resample(
+        &samples,
+        format.sample_rate,
+        config::AUDIO_DECODE_SAMPLE_RATE,
+    ))
+}
+
+// Resample mono samples that a caller already decoded to config::AUDIO_DECODE_SAMPLE_RATE
+pub fn pcm_resample(samples: &[f32], sample_rate: u32) -> Result<Vec<f32>> {
+    if !(config::MIN_RAW_PCM_SAMPLE_RATE..=config::MAX_RAW_PCM_SAMPLE_RATE).contains(&sample_rate) {
+        bail!(
+            "sample_rate must be between {} and {}, but got {}",
+            config::MIN_RAW_PCM_SAMPLE_RATE,
+            config::MAX_RAW_PCM_SAMPLE_RATE,
+            sample_rate
+        );
+    }
+    Ok(resample(
+        samples,
+        sample_rate,
+        config::AUDIO_DECODE_SAMPLE_RATE,
+    ))
+}
+
+// Decoders give us the channels interleaved, e.g. left, right, left, right...
+pub fn to_mono(
+    interleaved: &[f32],
+    channels: usize,
+    selection: ChannelSelection,
+) -> Result<Vec<f32>> {
+    let frames = interleaved.chunks_exact(channels);
+    let channel = match selection {
+        ChannelSelection::Downmix => {
+            return Ok(frames
+                .map(|frame| frame.iter().sum::<f32>() / channels as f32)
+                .collect());
+        }
+        ChannelSelection::Loudest => {
+            let energies: Vec<f64> = (0..channels)
+                .map(|channel| {
+                    frames
+                        .clone()
+                        .map(|frame| (frame[channel] as f64).powi(2))
+                        .sum()
+                })
+                .collect();
+            (0..channels)
+                .max_by(|a, b| energies[*a].total_cmp(&energies[*b]))
+                .unwrap_or_default()
+        }
+        ChannelSelection::Channel(channel) if channel >= channels => bail!(
+            "channel {channel} was requested, but the recording only has {channels} channel(s)"
+        ),
+        ChannelSelection::Channel(channel) => channel,
+    };
+    Ok(frames.map(|frame| frame[channel]).collect())
+}
+
+// How many zero crossings of the sinc function the resampling filter spans on
+// each side.  More gives a sharper cutoff at the cost of more work per sample.
+const RESAMPLE_ZERO_CROSSINGS: usize = 32;
+
+// Start rolling off a little below the Nyquist frequency, so that the filter
+// has room to reach the stopband before anything can alias.
+const RESAMPLE_ROLLOFF: f64 = 0.95;
+
+// The most filters (phases, see below) that we build for one resampling.
+// Rates like 44,101 Hz would otherwise need a filter for every output sample.
+const RESAMPLE_PHASES: usize = 256;
+
+// Resample with a Blackman-windowed sinc filter, see
+// https://ccrma.stanford.edu/~jos/resample/
+//
+// When the ratio of the two rates reduces to up/down, each output sample falls
+// at one of `up` fractional positions between the input samples, so we build
+// one filter (a "phase") for each position up front.  When there are more than
+// RESAMPLE_PHASES positions, we build that many evenly spaced filters instead,
+// and interpolate between the two on either side of each position.
+pub fn resample(samples: &[f32], from_rate: u32, to_rate: u32) -> Vec<f32> {
+    if from_rate == to_rate || samples.is_empty() {
+        return samples.to_vec();
+    }
+    let divisor = gcd(from_rate as usize, to_rate as usize);
+    let (up, down) = (to_rate as usize / divisor, from_rate as usize / divisor);
+    // The cutoff, as a fraction of the input's Nyquist frequency.  When downsampling,
+    // it has to be below the output's Nyquist frequency instead.
+    let cutoff = RESAMPLE_ROLLOFF * (up as f64 / down as f64).min(1.0);
+    let half_width = (RESAMPLE_ZERO_CROSSINGS as f64 / cutoff).ceil() as isize;
+
+    // The filters at each position, and one more at the next input sample for
+    // the positions after the last one to interpolate toward
+    let phase_count = up.min(RESAMPLE_PHASES);
+    let phases: Vec<Vec<f32>> = (0..=phase_count)
+        .map(|phase| {
+            let offset = phase as f64 / phase_count as f64;
+            let taps: Vec<f64> = (-half_width..=half_width)
+                .map(|tap| {
+                    let t = tap as f64 - offset;
+                    cutoff * sinc(cutoff * t) * blackman(t / (half_width as f64 + 1.0))
+                })
+                .collect();
+            // Keep the volume the same
+            let gain: f64 = taps.iter().sum();
+            taps.iter().map(|tap| (tap / gain) as f32).collect()
+        })
+        .collect();
+
+    let output_len = (samples.len() * up).div_ceil(down);
+    (0..output_len)
+        .map(|n| {
+            let position = n * down;
+            let phase = (position % up) as f64 * phase_count as f64 / up as f64;
+            let (below, above) = (&phases[phase as usize], &phases[phase as usize + 1]);
+            let weight = phase.fract() as f32;
+            // The filter is centered on the input sample just before this output
+            // sample, and hangs off the ends of the input at the edges
+            let first = (position / up) as isize - half_width;
+            let start = first.max(0) as usize;
+            let end = (first + below.len() as isize).clamp(0, samples.len() as isize) as usize;
+            let skipped = (start as isize - first) as usize;
+            below[skipped..]
+                .iter()
+                .zip(&above[skipped..])
+                .zip(&samples[start..end])
+                .map(|((below, above), sample)| (below + weight * (above - below)) * sample)
+                .sum()
+        })
+        .collect()
+}
+
+fn sinc(x: f64) -> f64 {
+    if x == 0.0 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+// The window is 1 at x = 0, and falls to 0 at x = ±1
+fn blackman(x: f64) -> f64 {
+    if x.abs() >= 1.0 {
+        return 0.0;
+    }
+    0.42 + 0.5 * (PI * x).cos() + 0.08 * (2.0 * PI * x).cos()
+}
+
+fn gcd(a: usize, b: usize) -> usize {
+    if b == 0 { a } else { gcd(b, a % b) }
+}
+
+// Estimate the signal-to-noise ratio of the samples in decibels, by comparing the
+// loudest frames (where someone is presumably speaking) to the quietest ones (where
+// there is presumably only background noise).
+pub fn signal_to_noise_ratio(samples: &[f32]) -> f64 {
+    // 20 millisecond frames
+    let frame_len = config::AUDIO_DECODE_SAMPLE_RATE as usize / 50;
+    let mut energies: Vec<f64> = samples
+        .chunks_exact(frame_len)
+        .map(|frame| frame.iter().map(|s| (*s as f64).powi(2)).sum::<f64>() / frame_len as f64)
+        .collect();
+    if energies.is_empty() {
+        return f64::INFINITY;
+    }
+    energies.sort_unstable_by(f64::total_cmp);
+    let percentile = |p: usize| energies[(energies.len() - 1) * p / 100];
+    let (noise, signal) = (percentile(10), percentile(95));
+    if noise == 0.0 {
+        return f64::INFINITY;
+    }
+    10.0 * (signal / noise).log10()
 }
 
 fn demux<R: Seek + Read>(original: R) -> Result<Track<R>> {
-    let stream = MatroskaFile::open(original).unwrap();
-    let first_track_option = stream.tracks().iter().find(|t| t.codec_id() == "A_OPUS");
+    let stream = MatroskaFile::open(original)?;
+    let first_track_option = stream
+        .tracks()
+        .iter()
+        .find(|t| t.codec_id() == "A_OPUS" || t.codec_id() == "A_VORBIS");
     let first_track = match first_track_option {
         Some(track) => track,
-        None => return Err(anyhow!("No Opus tracks in this file!")),
+        None => {
+            let codecs: Vec<&str> = stream.tracks().iter().map(|t| t.codec_id()).collect();
+            let detected = match codecs.as_slice() {
+                [] => "a Matroska file with no tracks".to_owned(),
+                _ => format!("a Matroska file with {} tracks", codecs.join(" and ")),
+            };
+            return Err(UnsupportedFormat::new(detected).into());
+        }
+    };
+    let codec = match first_track.codec_id() {
+        "A_VORBIS" => Codec::Vorbis(
+            first_track
+                .codec_private()
+                .ok_or_else(|| anyhow!("This Vorbis track has no header packets!"))?
+                .to_vec(),
+        ),
+        _ => Codec::Opus {
+            pre_skip: first_track
+                .codec_private()
+                .filter(|head| head.len() >= 12 && head.starts_with(b"OpusHead"))
+                .map_or(0, |head| u16::from_le_bytes([head[10], head[11]]) as usize),
+        },
     };
-    let sample_rate = first_track.audio().unwrap().sampling_frequency();
-    let channel_count = first_track.audio().unwrap().channels().get();
+    let audio = first_track
+        .audio()
+        .ok_or_else(|| anyhow!("This audio track does not say how it was recorded!"))?;
+    let sample_rate = audio.sampling_frequency();
+    let channel_count = audio.channels().get();
+    if matches!(codec, Codec::Opus { .. }) && channel_count > 2 {
+        return Err(UnsupportedFormat::new(format!(
+            "a Matroska file with {channel_count} channels of Opus audio"
+        ))
+        .into());
+    }
     Ok(Track {
         sample_rate,
         track: first_track.track_number().get(),
-        channels: if channel_count == 1 {
-            Channels::Mono
-        } else {
-            Channels::Stereo
-        },
+        channels: channel_count as usize,
+        codec,
+        timestamp_scale: stream.info().timestamp_scale().get(),
+        codec_delay: first_track.codec_delay().unwrap_or(0),
         reader: stream,
     })
 }
 
 #[cfg(test)]
-mod tests {
+#[allow(clippy::unnecessary_cast)]
+pub(crate) mod tests {
     use std::{fs::File, io::Cursor};
 
     use super::*;
 
+    // Decode a recording (webm, Ogg, MP4, WAV, FLAC or MP3) into mono samples at
+    // config::AUDIO_DECODE_SAMPLE_RATE, with the default decoders.
+    // Also returns the sample rate that the recording was made at.  For tests
+    // (here and in other modules) that read test_data.
+    pub(crate) fn pcm_decode<R: Seek + Read>(
+        original: R,
+        selection: ChannelSelection,
+    ) -> Result<(Vec<f32>, f64)> {
+        pcm_decode_at(original, selection, config::AUDIO_DECODE_SAMPLE_RATE)
+    }
+
+    pub(crate) fn pcm_decode_at<R: Seek + Read>(
+        original: R,
+        selection: ChannelSelection,
+        sample_rate: u32,
+    ) -> Result<(Vec<f32>, f64)> {
+        let mut original = original;
+        let mut recording = vec![];
+        original.read_to_end(&mut recording)?;
+        let decoded = DecoderRegistry::default().decode(&recording, None)?;
+        decoded_to_mono(decoded, selection, sample_rate)
+    }
+
     #[test]
     fn it_can_pcm_decode_mono() {
         let file = File::open("./test_data/portuguese/semana_de_arte_moderna_mono.webm").unwrap();
-        let (samples, rate) = pcm_decode(file).unwrap();
+        let (samples, rate) = pcm_decode(file, ChannelSelection::default()).unwrap();
         assert!(samples.len() > 50_000);
         assert_eq!(rate, 24_000 as f64);
     }
@@ -90,9 +809,36 @@ mod tests {
     #[test]
     fn it_can_pcm_decode_stereo() {
         let file = File::open("./test_data/portuguese/a_filha_do_patrao_stereo.webm").unwrap();
-        let (samples, rate) = pcm_decode(file).unwrap();
-        assert!(samples.len() > 40_000);
+        let (samples, rate) = pcm_decode(file, ChannelSelection::default()).unwrap();
+        // About 3 seconds of mono audio, rather than 6 seconds of interleaved channels
+        let seconds = samples.len() as f64 / config::AUDIO_DECODE_SAMPLE_RATE as f64;
+        assert!(seconds > 2.5 && seconds < 3.5);
         assert_eq!(rate, 24_000 as f64);
+        let file = File::open("./test_data/portuguese/a_filha_do_patrao_stereo.webm").unwrap();
+        let (right, _) = pcm_decode(file, ChannelSelection::Channel(1)).unwrap();
+        assert_eq!(right.len(), samples.len());
+    }
+
+    #[test]
+    fn it_turns_interleaved_channels_into_mono() {
+        let interleaved = [0.5, 0.1, -0.5, 0.1, 0.25, 0.3];
+        assert_eq!(
+            to_mono(&interleaved, 2, ChannelSelection::Downmix).unwrap(),
+            vec![0.3, -0.2, 0.275]
+        );
+        assert_eq!(
+            to_mono(&interleaved, 2, ChannelSelection::Loudest).unwrap(),
+            vec![0.5, -0.5, 0.25]
+        );
+        assert_eq!(
+            to_mono(&interleaved, 2, ChannelSelection::Channel(1)).unwrap(),
+            vec![0.1, 0.1, 0.3]
+        );
+        assert!(to_mono(&interleaved, 2, ChannelSelection::Channel(2)).is_err());
+        assert_eq!(
+            to_mono(&interleaved, 1, ChannelSelection::Downmix).unwrap(),
+            interleaved.to_vec()
+        );
     }
 
     #[test]
@@ -101,7 +847,7 @@ mod tests {
         let file =
             File::open("./test_data/russian/po_nedele_ni_slova_ni_s_kem_ne_skazhu_mono.webm")
                 .unwrap();
-        let (samples, rate) = pcm_decode(file).unwrap();
+        let (samples, rate) = pcm_decode(file, ChannelSelection::default()).unwrap();
         assert!(samples.len() > 40_000);
         assert_eq!(rate, 48_000 as f64);
     }
@@ -110,7 +856,7 @@ mod tests {
     #[allow(non_snake_case)]
     fn it_can_pcm_decode_sample_rate_of_8_MHz() {
         let file = File::open("./test_data/russian/voron_mono_8MHz.webm").unwrap();
-        let (_, rate) = pcm_decode(file).unwrap();
+        let (_, rate) = pcm_decode(file, ChannelSelection::default()).unwrap();
         assert_eq!(rate, 8_000 as f64);
     }
 
@@ -120,14 +866,14 @@ mod tests {
         // I got it using the MediaRecorder API in firefox on a mac
         // The matroska_demuxer crate can handle it, the symphonia crate cannot.
         let file = File::open("./test_data/firefox.webm").unwrap();
-        let (_, rate) = pcm_decode(file).unwrap();
+        let (_, rate) = pcm_decode(file, ChannelSelection::default()).unwrap();
         assert_eq!(rate, 44_100 as f64);
     }
 
     #[test]
     fn it_can_decode_webm_recorded_in_edge() {
         let file = File::open("./test_data/edge.webm").unwrap();
-        let (_, rate) = pcm_decode(file).unwrap();
+        let (_, rate) = pcm_decode(file, ChannelSelection::default()).unwrap();
         assert_eq!(rate, 48_000 as f64);
     }
 
@@ -136,13 +882,338 @@ mod tests {
         let binary_data =
             std::fs::read("./test_data/english/alexander_the_great_mono.webm").unwrap();
         let cursor = Cursor::new(binary_data);
-        let (_, rate) = pcm_decode(cursor).unwrap();
+        let (_, rate) = pcm_decode(cursor, ChannelSelection::default()).unwrap();
         assert_eq!(rate, 12_000 as f64);
     }
 
+    fn sine(frequency: f64, sample_rate: u32, seconds: f64) -> Vec<f32> {
+        let len = (sample_rate as f64 * seconds) as usize;
+        (0..len)
+            .map(|i| (2.0 * PI * frequency * i as f64 / sample_rate as f64).sin() as f32)
+            .collect()
+    }
+
+    // The largest difference between two signals, ignoring the edges where the
+    // filter runs out of input
+    fn max_error(actual: &[f32], expected: &[f32]) -> f32 {
+        let edge = actual.len() / 10;
+        actual[edge..actual.len() - edge]
+            .iter()
+            .zip(&expected[edge..])
+            .map(|(a, e)| (a - e).abs())
+            .fold(0.0, f32::max)
+    }
+
+    #[test]
+    fn it_resamples_a_tone_to_16_khz() {
+        for from_rate in [48_000, 44_100, 12_000, 8_000] {
+            let resampled = resample(&sine(440.0, from_rate, 0.5), from_rate, 16_000);
+            assert_eq!(resampled.len(), 8_000);
+            let error = max_error(&resampled, &sine(440.0, 16_000, 0.5));
+            assert!(error < 0.01, "{from_rate} Hz: error of {error}");
+        }
+    }
+
+    #[test]
+    fn it_resamples_from_rates_that_do_not_divide_evenly() {
+        // 44,101 and 191,999 Hz would need 16,000 filters each, without interpolation
+        for from_rate in [44_101, 191_999] {
+            let resampled = resample(&sine(440.0, from_rate, 0.5), from_rate, 16_000);
+            let error = max_error(&resampled, &sine(440.0, 16_000, 0.5));
+            assert!(error < 0.01, "{from_rate} Hz: error of {error}");
+        }
+    }
+
+    #[test]
+    fn it_filters_out_frequencies_that_would_alias() {
+        // 10 kHz is above the Nyquist frequency of 16 kHz audio, so it would
+        // otherwise come back as a 6 kHz tone
+        let resampled = resample(&sine(10_000.0, 48_000, 0.5), 48_000, 16_000);
+        assert!(max_error(&resampled, &[0.0; 8_000]) < 0.01);
+    }
+
+    #[test]
+    fn it_does_not_resample_at_the_same_rate() {
+        let samples = sine(440.0, 16_000, 0.1);
+        assert_eq!(resample(&samples, 16_000, 16_000), samples);
+        assert!(resample(&[], 48_000, 16_000).is_empty());
+    }
+
+    #[test]
+    fn it_estimates_a_high_signal_to_noise_ratio_for_a_clear_recording() {
+        let file = File::open("./test_data/english/complete_book_of_cheese_mono.webm").unwrap();
+        let (samples, _) = pcm_decode(file, ChannelSelection::default()).unwrap();
+        assert!(signal_to_noise_ratio(&samples) > config::MIN_SNR_DB);
+    }
+
     #[test]
-    fn it_errors_on_vorbis_encoding() {
+    fn it_estimates_a_low_signal_to_noise_ratio_for_noise() {
+        use rand::{Rng, SeedableRng};
+        let mut rng = rand::rngs::StdRng::seed_from_u64(1);
+        let noise: Vec<f32> = (0..48_000).map(|_| rng.random_range(-0.5..0.5)).collect();
+        assert!(signal_to_noise_ratio(&noise) < config::MIN_SNR_DB);
+        assert_eq!(signal_to_noise_ratio(&[0.0; 48_000]), f64::INFINITY);
+        assert_eq!(signal_to_noise_ratio(&[]), f64::INFINITY);
+    }
+
+    #[test]
+    fn it_can_pcm_decode_ogg_opus() {
+        let ogg = ogg::tests::webm_to_ogg("./test_data/english/complete_book_of_cheese_mono.webm");
+        let (samples, rate) = pcm_decode(Cursor::new(ogg), ChannelSelection::default()).unwrap();
+        let file = File::open("./test_data/english/complete_book_of_cheese_mono.webm").unwrap();
+        let (webm_samples, _) = pcm_decode(file, ChannelSelection::default()).unwrap();
+        assert_eq!(rate, 24_000_f64);
+        // The same audio, give or take the pre-skip
+        let difference = samples.len().abs_diff(webm_samples.len());
+        assert!(difference < config::AUDIO_DECODE_SAMPLE_RATE as usize / 50);
+    }
+
+    #[test]
+    fn it_can_pcm_decode_aac_in_mp4() {
+        let file = File::open("./test_data/english/complete_book_of_cheese_mono_aac.mp4").unwrap();
+        let (samples, rate) = pcm_decode(file, ChannelSelection::default()).unwrap();
+        assert_eq!(rate, 24_000_f64);
+        let seconds = samples.len() as f64 / config::AUDIO_DECODE_SAMPLE_RATE as f64;
+        assert!(seconds > 4.9 && seconds < 5.2, "{seconds} seconds");
+    }
+
+    #[test]
+    fn it_can_pcm_decode_lab_formats() {
+        for path in [
+            "./test_data/english/complete_book_of_cheese_mono.flac",
+            "./test_data/english/complete_book_of_cheese_mono.mp3",
+        ] {
+            let file = File::open(path).unwrap();
+            let (samples, rate) = pcm_decode(file, ChannelSelection::default()).unwrap();
+            assert_eq!(rate, 24_000_f64);
+            let seconds = samples.len() as f64 / config::AUDIO_DECODE_SAMPLE_RATE as f64;
+            assert!(seconds > 4.9 && seconds < 5.2, "{path}: {seconds} seconds");
+        }
+
+        // A stereo WAV at 44.1 kHz, with a tone in the right channel only
+        let wav = lab::tests::wav(lab::tests::WavEncoding::Pcm24, 44_100, &[0.0, 440.0], 1.0);
+        let (right, rate) = pcm_decode(Cursor::new(wav), ChannelSelection::Channel(1)).unwrap();
+        assert_eq!(rate, 44_100_f64);
+        assert_eq!(right.len(), config::AUDIO_DECODE_SAMPLE_RATE as usize);
+        let loudest = right.iter().fold(0.0_f32, |a, b| a.max(b.abs()));
+        assert!((loudest - 0.5).abs() < 0.01, "{loudest}");
+    }
+
+    fn s16le(samples: &[f32]) -> Vec<u8> {
+        samples
+            .iter()
+            .flat_map(|sample| ((sample * 32767.0) as i16).to_le_bytes())
+            .collect()
+    }
+
+    #[test]
+    fn it_can_decode_raw_pcm_frames() {
+        // Half a second of a quiet left channel and a loud right channel, at 48 kHz
+        let interleaved: Vec<f32> = (0..24_000).flat_map(|_| [0.1, 0.5]).collect();
+        let s16 = RawPcm {
+            sample_rate: 48_000,
+            channels: 2,
+            sample_format: SampleFormat::S16le,
+        };
+        let samples =
+            raw_pcm_decode(&s16le(&interleaved), &s16, ChannelSelection::Loudest).unwrap();
+        let rate = config::AUDIO_DECODE_SAMPLE_RATE as usize;
+        assert_eq!(samples.len(), rate / 2);
+        assert!((samples[rate / 4] - 0.5).abs() < 1e-3);
+
+        // Already at our rate, so only the format changes
+        let f32 = RawPcm {
+            sample_rate: config::AUDIO_DECODE_SAMPLE_RATE,
+            channels: 1,
+            sample_format: SampleFormat::F32le,
+        };
+        let bytes: Vec<u8> = [0.25_f32, -0.5]
+            .iter()
+            .flat_map(|s| s.to_le_bytes())
+            .collect();
+        assert_eq!(
+            raw_pcm_decode(&bytes, &f32, ChannelSelection::default()).unwrap(),
+            vec![0.25, -0.5]
+        );
+    }
+
+    #[test]
+    fn it_errors_on_raw_pcm_that_ends_partway_through_a_frame() {
+        let format = RawPcm {
+            sample_rate: 16_000,
+            channels: 2,
+            sample_format: SampleFormat::S16le,
+        };
+        assert!(raw_pcm_decode(&[0; 6], &format, ChannelSelection::default()).is_err());
+        assert!(raw_pcm_decode(&[0; 8], &format, ChannelSelection::default()).is_ok());
+    }
+
+    #[test]
+    fn it_parses_the_input_setting() {
+        let input: Input = serde_json::from_str(
+            r#"{"format": "pcm", "sample_rate": 48000, "channels": 1, "sample_format": "f32le"}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            input,
+            Input::Pcm(RawPcm {
+                sample_rate: 48_000,
+                channels: 1,
+                sample_format: SampleFormat::F32le
+            })
+        );
+        let input: Input = serde_json::from_str(r#"{"format": "recording"}"#).unwrap();
+        assert_eq!(input, Input::default());
+        assert!(
+            serde_json::from_str::<Input>(
+                r#"{"format": "pcm", "sample_rate": 48000, "channels": 1, "sample_format": "u8"}"#
+            )
+            .is_err()
+        );
+
+        let format = RawPcm {
+            sample_rate: 48_000,
+            channels: 0,
+            sample_format: SampleFormat::S16le,
+        };
+        assert!(format.validate().is_err());
+        assert!(
+            RawPcm {
+                channels: 1,
+                ..format
+            }
+            .validate()
+            .is_ok()
+        );
+        assert!(
+            RawPcm {
+                channels: 1,
+                sample_rate: 100,
+                ..format
+            }
+            .validate()
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn it_can_pcm_decode_vorbis_encoding() {
         let file = File::open("./test_data/vorbis.webm").unwrap();
-        assert!(pcm_decode(file).is_err());
+        let (samples, rate) = pcm_decode(file, ChannelSelection::default()).unwrap();
+        assert_eq!(rate, 44_100_f64);
+        let seconds = samples.len() as f64 / config::AUDIO_DECODE_SAMPLE_RATE as f64;
+        assert!(seconds > 5.5 && seconds < 6.5, "{seconds} seconds");
+        // The recording has sound in it, not just silence
+        let loudest = samples.iter().fold(0.0_f32, |a, b| a.max(b.abs()));
+        assert!(loudest > 0.01);
+    }
+
+    #[test]
+    fn it_takes_the_vorbis_sample_rate_from_its_own_headers() {
+        let mut webm = std::fs::read("./test_data/vorbis.webm").unwrap();
+        // Say that the track is 8 kHz, like a track without a SamplingFrequency
+        let rate = [&[0xb5, 0x88][..], &44_100_f64.to_be_bytes()].concat();
+        let at = webm.windows(rate.len()).position(|w| w == rate).unwrap();
+        webm[at + 2..at + 10].copy_from_slice(&8_000_f64.to_be_bytes());
+        let decoded = MatroskaDecoder.decode(&webm).unwrap();
+        assert_eq!(decoded.sample_rate, 44_100);
+        assert_eq!(decoded.original_sample_rate, 44_100_f64);
+        let seconds = decoded.interleaved.len() as f64 / decoded.channels as f64 / 44_100.0;
+        assert!(seconds > 5.5 && seconds < 6.5, "{seconds} seconds");
+    }
+
+    // The Opus packets of a webm recording's first track
+    fn opus_packets(path: &str) -> Vec<Vec<u8>> {
+        let mut webm = MatroskaFile::open(File::open(path).unwrap()).unwrap();
+        let mut packets = vec![];
+        let mut frame = Frame::default();
+        while webm.next_frame(&mut frame).unwrap() {
+            packets.push(frame.data.clone());
+        }
+        packets
+    }
+
+    #[test]
+    fn it_conceals_damaged_opus_packets() {
+        let packets = opus_packets("./test_data/english/complete_book_of_cheese_mono.webm");
+        let mut whole = vec![];
+        let mut stream = OpusStream::new(Channels::Mono).unwrap();
+        for packet in &packets {
+            stream.decode_packet(packet, &mut whole);
+        }
+        assert_eq!(stream.finish(), 0);
+
+        // Every tenth packet is garbled into one that says it has more frames
+        // than it has bytes, which the decoder rejects
+        let mut damaged = vec![];
+        let mut stream = OpusStream::new(Channels::Mono).unwrap();
+        for (i, packet) in packets.iter().enumerate() {
+            let packet = if i % 10 == 5 { &[0x03][..] } else { packet };
+            stream.decode_packet(packet, &mut damaged);
+        }
+        let garbled = (0..packets.len()).filter(|i| i % 10 == 5).count();
+        assert_eq!(stream.finish(), garbled);
+        // The lost packets were filled in, so the timing of the rest is right
+        assert_eq!(damaged.len(), whole.len());
+    }
+
+    #[test]
+    fn it_keeps_what_it_decoded_of_a_truncated_webm() {
+        let recording = std::fs::read("./test_data/edge.webm").unwrap();
+        let whole = MatroskaDecoder.decode(&recording).unwrap();
+        let truncated = MatroskaDecoder
+            .decode(&recording[..recording.len() * 2 / 3])
+            .unwrap();
+        assert!(truncated.interleaved.len() > whole.interleaved.len() / 2);
+        assert!(truncated.interleaved.len() < whole.interleaved.len());
+    }
+
+    #[test]
+    fn it_places_opus_packets_on_the_timeline_of_their_blocks() {
+        let recording =
+            std::fs::read("./test_data/english/complete_book_of_cheese_mono.webm").unwrap();
+        let decoded = MatroskaDecoder.decode(&recording).unwrap();
+        // 251 packets of 20 ms, back to back, less the 312 samples of pre-skip
+        assert_eq!(decoded.interleaved.len(), 251 * 960 - 312);
+    }
+
+    #[test]
+    fn it_does_not_fill_in_gaps_from_damaged_timestamps() {
+        let recording =
+            std::fs::read("./test_data/english/complete_book_of_cheese_mono.webm").unwrap();
+        let whole = MatroskaDecoder.decode(&recording).unwrap();
+        // Swap the first cluster's CRC-32 and 1 byte timestamp (7 ms) for a 7
+        // byte timestamp of an hour, and then of 10^12 ms
+        let crc_and_timestamp = [0xbf, 0x84, 0xb4, 0xe7, 0x13, 0x79, 0xe7, 0x81, 0x07];
+        let at = recording
+            .windows(crc_and_timestamp.len())
+            .position(|window| window == crc_and_timestamp)
+            .unwrap();
+        for timestamp in [3_600_000_u64, 1_000_000_000_000] {
+            let mut damaged = recording.clone();
+            damaged[at..at + 2].copy_from_slice(&[0xe7, 0x87]);
+            damaged[at + 2..at + 9].copy_from_slice(&timestamp.to_be_bytes()[1..]);
+            let decoded = MatroskaDecoder.decode(&damaged).unwrap();
+            assert!(decoded.interleaved.len() <= whole.interleaved.len() + 960);
+        }
+    }
+
+    #[test]
+    fn it_rejects_sample_rates_and_channels_that_a_header_made_up() {
+        for (sample_rate, channels) in [(0, 1), (1_000_003, 1), (16_000, 0), (16_000, 100)] {
+            let decoded = DecodedAudio {
+                interleaved: vec![0.0; 4_000],
+                channels,
+                sample_rate,
+                original_sample_rate: sample_rate as f64,
+                dropped_packets: 0,
+            };
+            let err = decoded_to_mono(decoded, ChannelSelection::default(), 16_000).unwrap_err();
+            assert!(err.downcast_ref::<UnsupportedFormat>().is_some());
+        }
+        // And a WAV file that says its rate is 0
+        let mut wav = lab::tests::wav(lab::tests::WavEncoding::Pcm16, 16_000, &[440.0], 0.25);
+        wav[24..28].copy_from_slice(&0_u32.to_le_bytes());
+        assert!(pcm_decode(Cursor::new(wav), ChannelSelection::default()).is_err());
     }
 }
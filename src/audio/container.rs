@@ -0,0 +1,70 @@
+// This module is responsible for the part of decoding that is the same for
+// every container that the symphonia crate demuxes for us (MP4, WAV, FLAC and
+// MP3): find the first audio track, make a decoder for it, and decode each of
+// its packets into interleaved samples.  The mp4 and lab modules say how to
+// open their containers, and which codecs they accept inside them.
+
+use std::io::Cursor;
+
+use anyhow::{Result, anyhow};
+use symphonia::core::{
+    codecs::audio::{AudioCodecParameters, AudioDecoder},
+    formats::{FormatOptions, FormatReader, TrackType},
+    io::MediaSourceStream,
+};
+
+use super::{DecodedAudio, check_length};
+
+// Decode the first audio track of a file into interleaved samples.  `name` is
+// what kind of file it is, for errors, e.g. "MP4".  `make_decoder` should
+// return an UnsupportedFormat error for codecs that we don't decode.
+pub(super) fn decode_first_track(
+    bytes: Vec<u8>,
+    name: &str,
+    open: impl FnOnce(MediaSourceStream<'static>, FormatOptions) -> Result<Box<dyn FormatReader>>,
+    make_decoder: impl FnOnce(&AudioCodecParameters) -> Result<Box<dyn AudioDecoder>>,
+) -> Result<DecodedAudio> {
+    // symphonia needs to own a reader that it can send between threads
+    let stream = MediaSourceStream::new(Box::new(Cursor::new(bytes)), Default::default());
+    let mut demuxer = open(stream, FormatOptions::default())?;
+
+    let track = demuxer
+        .default_track(TrackType::Audio)
+        .ok_or_else(|| anyhow!("No audio tracks in this {name} file!"))?;
+    let track_id = track.id;
+    let params = track
+        .codec_params
+        .as_ref()
+        .and_then(|params| params.audio())
+        .ok_or_else(|| anyhow!("No audio tracks in this {name} file!"))?;
+    let mut decoder = make_decoder(params)?;
+
+    let mut pcm_data = vec![];
+    let mut decoded = vec![];
+    let mut channels = params
+        .channels
+        .as_ref()
+        .map_or(1, |channels| channels.count());
+    let mut sample_rate = params.sample_rate;
+    while let Some(packet) = demuxer.next_packet()? {
+        if packet.track_id != track_id {
+            continue;
+        }
+        let buffer = decoder.decode(&packet)?;
+        channels = buffer.spec().channels().count();
+        sample_rate = Some(buffer.spec().rate());
+        buffer.copy_to_vec_interleaved(&mut decoded);
+        pcm_data.extend_from_slice(&decoded);
+        check_length(pcm_data.len(), channels, buffer.spec().rate())?;
+    }
+    let sample_rate = sample_rate
+        .ok_or_else(|| anyhow!("This {name} file does not say what its sample rate is"))?;
+
+    Ok(DecodedAudio {
+        interleaved: pcm_data,
+        channels,
+        sample_rate,
+        original_sample_rate: sample_rate as f64,
+        dropped_packets: 0,
+    })
+}
@@ -0,0 +1,239 @@
+// This module is responsible for decoding the files that staff export from
+// Audacity and from the digitization lab: WAV (16 or 24 bit integer, or 32 bit
+// float PCM), FLAC and MP3.  The symphonia crate does the demuxing and decoding.
+//
+// Unlike the browser recordings, these files are made at whatever rate the lab
+// likes (usually 44.1 or 48 kHz), so we hand back the samples at that rate and
+// let `resample` sort it out.
+
+use std::io::Read;
+
+use anyhow::{Context, Result};
+use symphonia::{
+    core::{codecs::audio::AudioDecoderOptions, formats::FormatReader},
+    default::{
+        formats::{FlacReader, MpaReader, WavReader},
+        get_codecs,
+    },
+};
+
+use super::{
+    DecodedAudio, container,
+    registry::{AudioDecoder, UnsupportedFormat},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) enum Format {
+    Wav,
+    Flac,
+    Mp3,
+}
+
+impl Format {
+    // Recognize a file from its first 12 bytes
+    pub(super) fn sniff(magic: &[u8]) -> Option<Format> {
+        match magic {
+            _ if magic.starts_with(b"RIFF") && magic.get(8..12) == Some(b"WAVE") => {
+                Some(Format::Wav)
+            }
+            _ if magic.starts_with(b"fLaC") => Some(Format::Flac),
+            // An ID3 tag, or else the sync bits at the start of an MPEG audio frame.
+            // Raw AAC (ADTS) frames have the same sync bits, but layer bits of 0.
+            _ if magic.starts_with(b"ID3") => Some(Format::Mp3),
+            [0xff, second, ..] if second & 0xe0 == 0xe0 && second & 0x06 != 0 => Some(Format::Mp3),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Format::Wav => "WAV",
+            Format::Flac => "FLAC",
+            Format::Mp3 => "MP3",
+        }
+    }
+}
+
+pub(super) struct LabDecoder(pub(super) Format);
+
+impl AudioDecoder for LabDecoder {
+    fn name(&self) -> &str {
+        self.0.name()
+    }
+
+    fn sniff(&self, recording: &[u8]) -> bool {
+        Format::sniff(recording) == Some(self.0)
+    }
+
+    fn mime_types(&self) -> &[&str] {
+        match self.0 {
+            Format::Wav => &["audio/wav", "audio/x-wav", "audio/wave", "audio/vnd.wave"],
+            Format::Flac => &["audio/flac", "audio/x-flac"],
+            Format::Mp3 => &["audio/mpeg", "audio/mp3"],
+        }
+    }
+
+    fn decode(&self, recording: &[u8]) -> Result<DecodedAudio> {
+        decode(recording, self.0)
+    }
+}
+
+// Decode the first audio track of a file into interleaved samples
+fn decode(mut reader: impl Read, format: Format) -> Result<DecodedAudio> {
+    let mut bytes = vec![];
+    reader.read_to_end(&mut bytes)?;
+    container::decode_first_track(
+        bytes,
+        format.name(),
+        |stream, options| -> Result<Box<dyn FormatReader>> {
+            Ok(match format {
+                Format::Wav => Box::new(WavReader::try_new(stream, options)?),
+                Format::Flac => Box::new(FlacReader::try_new(stream, options)?),
+                Format::Mp3 => Box::new(MpaReader::try_new(stream, options)?),
+            })
+        },
+        |params| {
+            // e.g. a WAV file with ADPCM audio
+            if get_codecs().get_audio_decoder(params.codec).is_none() {
+                return Err(UnsupportedFormat::new(format!(
+                    "a {} file with audio in codec {}",
+                    format.name(),
+                    params.codec
+                ))
+                .into());
+            }
+            get_codecs()
+                .make_audio_decoder(params, &AudioDecoderOptions::default())
+                .with_context(|| {
+                    format!("Could not decode the audio in this {} file", format.name())
+                })
+        },
+    )
+}
+
+#[cfg(test)]
+pub(in crate::audio) mod tests {
+    use std::{f32::consts::PI, fs::File, io::Cursor};
+
+    use super::*;
+    use crate::config;
+
+    // The sample formats that Audacity can export to WAV
+    #[derive(Clone, Copy)]
+    pub(in crate::audio) enum WavEncoding {
+        Pcm16,
+        Pcm24,
+        Float32,
+    }
+
+    // Make a WAV file with the given channels, each a sine wave of the given frequency
+    pub(in crate::audio) fn wav(
+        encoding: WavEncoding,
+        sample_rate: u32,
+        frequencies: &[f32],
+        seconds: f32,
+    ) -> Vec<u8> {
+        let (format_tag, bits): (u16, u16) = match encoding {
+            WavEncoding::Pcm16 => (1, 16),
+            WavEncoding::Pcm24 => (1, 24),
+            WavEncoding::Float32 => (3, 32),
+        };
+        let channels = frequencies.len() as u16;
+        let block_align = channels * bits / 8;
+        let mut data = vec![];
+        for n in 0..(sample_rate as f32 * seconds) as usize {
+            for frequency in frequencies {
+                let sample = 0.5 * (2.0 * PI * frequency * n as f32 / sample_rate as f32).sin();
+                match encoding {
+                    WavEncoding::Pcm16 => {
+                        data.extend_from_slice(&((sample * 32767.0) as i16).to_le_bytes())
+                    }
+                    WavEncoding::Pcm24 => {
+                        data.extend_from_slice(&((sample * 8388607.0) as i32).to_le_bytes()[..3])
+                    }
+                    WavEncoding::Float32 => data.extend_from_slice(&sample.to_le_bytes()),
+                }
+            }
+        }
+
+        let mut wav = vec![];
+        wav.extend_from_slice(b"RIFF");
+        wav.extend_from_slice(&(36 + data.len() as u32).to_le_bytes());
+        wav.extend_from_slice(b"WAVEfmt ");
+        wav.extend_from_slice(&16_u32.to_le_bytes());
+        wav.extend_from_slice(&format_tag.to_le_bytes());
+        wav.extend_from_slice(&channels.to_le_bytes());
+        wav.extend_from_slice(&sample_rate.to_le_bytes());
+        wav.extend_from_slice(&(sample_rate * block_align as u32).to_le_bytes());
+        wav.extend_from_slice(&block_align.to_le_bytes());
+        wav.extend_from_slice(&bits.to_le_bytes());
+        wav.extend_from_slice(b"data");
+        wav.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        wav.extend_from_slice(&data);
+        wav
+    }
+
+    #[test]
+    fn it_recognizes_formats_from_their_first_bytes() {
+        assert_eq!(
+            Format::sniff(b"RIFF\x24\x08\0\0WAVEfmt "),
+            Some(Format::Wav)
+        );
+        assert_eq!(Format::sniff(b"fLaC\0\0\0\x22"), Some(Format::Flac));
+        assert_eq!(Format::sniff(b"ID3\x04\0\0\0\0"), Some(Format::Mp3));
+        assert_eq!(Format::sniff(&[0xff, 0xf3, 0x64, 0xc4]), Some(Format::Mp3));
+        // Matroska, raw AAC (ADTS) frames, and a RIFF file that isn't a WAV
+        assert_eq!(Format::sniff(&[0x1a, 0x45, 0xdf, 0xa3]), None);
+        assert_eq!(Format::sniff(&[0xff, 0xf1, 0x50, 0x80]), None);
+        assert_eq!(Format::sniff(&[0xff, 0xf9, 0x50, 0x80]), None);
+        assert_eq!(Format::sniff(b"RIFF\x24\x08\0\0AVI LIST"), None);
+    }
+
+    #[test]
+    fn it_decodes_each_wav_encoding() {
+        for encoding in [WavEncoding::Pcm16, WavEncoding::Pcm24, WavEncoding::Float32] {
+            let file = wav(encoding, 44_100, &[440.0, 880.0], 0.5);
+            let decoded = decode(Cursor::new(file), Format::Wav).unwrap();
+            assert_eq!(decoded.channels, 2);
+            assert_eq!(decoded.sample_rate, 44_100);
+            assert_eq!(decoded.interleaved.len(), 2 * 22_050);
+            // The 100th sample of the left channel
+            let expected = 0.5 * (2.0 * PI * 440.0 * 100.0 / 44_100.0).sin();
+            assert!((decoded.interleaved[200] - expected).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn it_decodes_flac() {
+        let file = File::open("./test_data/english/complete_book_of_cheese_mono.flac").unwrap();
+        let decoded = decode(file, Format::Flac).unwrap();
+        assert_eq!(decoded.channels, 1);
+        assert_eq!(decoded.sample_rate, 24_000);
+        // FLAC is lossless, so every sample of the original is there
+        assert_eq!(decoded.interleaved.len(), 120_480);
+    }
+
+    #[test]
+    fn it_decodes_mp3() {
+        let file = File::open("./test_data/english/complete_book_of_cheese_mono.mp3").unwrap();
+        let decoded = decode(file, Format::Mp3).unwrap();
+        assert_eq!(decoded.channels, 1);
+        assert_eq!(decoded.sample_rate, 24_000);
+        let seconds = decoded.interleaved.len() as f64 / 24_000.0;
+        assert!(seconds > 4.9 && seconds < 5.2, "{seconds} seconds");
+    }
+
+    #[test]
+    fn it_stops_decoding_recordings_that_are_too_long() {
+        let seconds = config::MAX_RECORDING_SECONDS as f32 + 1.0;
+        let file = wav(WavEncoding::Pcm16, 8_000, &[0.0], seconds);
+        let err = decode(Cursor::new(file), Format::Wav).unwrap_err();
+        assert!(err.to_string().contains("at most"), "{err}");
+    }
+
+    #[test]
+    fn it_errors_on_files_that_are_not_what_they_seem() {
+        assert!(decode(Cursor::new(b"RIFF\0\0\0\0WAVE"), Format::Wav).is_err());
+        assert!(decode(Cursor::new(b"fLaC"), Format::Flac).is_err());
+    }
+}
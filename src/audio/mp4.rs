@@ -0,0 +1,101 @@
+// This module is responsible for reading AAC audio out of an MP4 (aka ISO-BMFF)
+// file, which is what Safari's MediaRecorder makes.  The symphonia crate does
+// the demuxing and decoding in pure rust, so we don't need any system codecs.
+//
+// An MP4 file is a series of boxes, and the first one is usually "ftyp", which
+// says what kind of MP4 it is.  Safari records AAC-LC, and we don't try to
+// decode any other codec that an MP4 might contain.
+
+use std::io::Read;
+
+use anyhow::{Context, Result};
+use symphonia::{
+    core::{
+        codecs::audio::{self, AudioDecoderOptions, well_known::CODEC_ID_AAC},
+        formats::FormatReader,
+    },
+    default::{codecs::AacDecoder, formats::IsoMp4Reader},
+};
+
+use super::{
+    DecodedAudio, container,
+    registry::{AudioDecoder, UnsupportedFormat},
+};
+
+// The type of the first box, which comes after its 4 byte size
+const MAGIC: &[u8] = b"ftyp";
+
+pub(super) struct Mp4AacDecoder;
+
+impl AudioDecoder for Mp4AacDecoder {
+    fn name(&self) -> &str {
+        "MP4 AAC"
+    }
+
+    fn sniff(&self, recording: &[u8]) -> bool {
+        recording.get(4..8) == Some(MAGIC)
+    }
+
+    fn mime_types(&self) -> &[&str] {
+        &["audio/mp4", "video/mp4", "audio/x-m4a", "audio/m4a"]
+    }
+
+    fn decode(&self, recording: &[u8]) -> Result<DecodedAudio> {
+        decode(recording)
+    }
+}
+
+// Decode the first AAC track of an MP4 file into interleaved samples
+fn decode(mut reader: impl Read) -> Result<DecodedAudio> {
+    let mut bytes = vec![];
+    reader.read_to_end(&mut bytes)?;
+    container::decode_first_track(
+        bytes,
+        "MP4",
+        |stream, options| -> Result<Box<dyn FormatReader>> {
+            Ok(Box::new(
+                IsoMp4Reader::try_new(stream, options).context("Could not read this MP4 file")?,
+            ))
+        },
+        |params| -> Result<Box<dyn audio::AudioDecoder>> {
+            if params.codec != CODEC_ID_AAC {
+                return Err(UnsupportedFormat::new(format!(
+                    "an MP4 file with audio in codec {} rather than AAC",
+                    params.codec
+                ))
+                .into());
+            }
+            Ok(Box::new(AacDecoder::try_new(
+                params,
+                &AudioDecoderOptions::default(),
+            )?))
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+
+    use super::*;
+
+    #[test]
+    fn it_decodes_aac_in_mp4() {
+        let file = File::open("./test_data/english/complete_book_of_cheese_mono_aac.mp4").unwrap();
+        let decoded = decode(file).unwrap();
+        assert_eq!(decoded.channels, 1);
+        assert_eq!(decoded.sample_rate, 24_000);
+        // The recording has speech in it, not just silence
+        let loudest = decoded
+            .interleaved
+            .iter()
+            .fold(0.0_f32, |a, b| a.max(b.abs()));
+        assert!(loudest > 0.1);
+    }
+
+    #[test]
+    fn it_errors_on_files_that_are_not_mp4() {
+        let file = File::open("./test_data/english/complete_book_of_cheese_mono.webm").unwrap();
+        assert!(decode(file).is_err());
+    }
+}
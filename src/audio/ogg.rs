@@ -0,0 +1,390 @@
+// This module is responsible for reading Opus audio out of an Ogg file, which
+// is what Firefox's MediaRecorder makes when asked for `audio/ogg; codecs=opus`.
+//
+// An Ogg file is a series of pages, each of which starts with "OggS".  A page
+// carries the segments of one or more packets, and a packet may continue onto
+// the next page.  See https://www.rfc-editor.org/rfc/rfc3533 for the pages, and
+// https://www.rfc-editor.org/rfc/rfc7845 for how Opus is stored in them: an
+// OpusHead packet, an OpusTags packet, and then the audio packets.
+//
+// Granule positions count samples at 48 kHz, including the "pre-skip" samples at
+// the start that the encoder needed to warm up.  We drop the pre-skip samples,
+// and use the last granule position to trim the padding off the end.
+
+use std::io::Read;
+
+use anyhow::{Result, bail};
+use opus::Channels;
+
+use super::{
+    DecodedAudio, OpusStream, check_length,
+    registry::{AudioDecoder, UnsupportedFormat},
+};
+use crate::config;
+
+const MAGIC: &[u8; 4] = b"OggS";
+
+const HEADER_LEN: usize = 27;
+const CONTINUED_PACKET: u8 = 0x01;
+const END_OF_STREAM: u8 = 0x04;
+
+// Granule positions are always at this rate, whatever rate the decoder runs at
+const GRANULE_SAMPLE_RATE: u32 = 48_000;
+
+struct Page {
+    header_type: u8,
+    granule_position: Option<u64>,
+    serial: u32,
+    segments: Vec<u8>,
+    data: Vec<u8>,
+}
+
+// Read the next page, or None at the end of the file.  A page that was cut off
+// partway (e.g. an upload that was interrupted) counts as the end of the file.
+fn read_page(reader: &mut impl Read) -> Result<Option<Page>> {
+    let mut header = [0; HEADER_LEN];
+    if !read_fully(reader, &mut header)? {
+        return Ok(None);
+    }
+    if &header[..4] != MAGIC {
+        bail!("Expected an Ogg page, but found {:?}", &header[..4]);
+    }
+    let mut segments = vec![0; header[26] as usize];
+    if !read_fully(reader, &mut segments)? {
+        return Ok(None);
+    }
+    let mut data = vec![0; segments.iter().map(|s| *s as usize).sum()];
+    if !read_fully(reader, &mut data)? {
+        return Ok(None);
+    }
+
+    let expected_checksum = u32::from_le_bytes(header[22..26].try_into()?);
+    header[22..26].fill(0);
+    let checksum = [&header[..], &segments, &data]
+        .iter()
+        .fold(0, |crc, bytes| crc32(crc, bytes));
+    if checksum != expected_checksum {
+        bail!("Ogg page has a bad checksum");
+    }
+
+    // -1 means that no packet ends on this page
+    let granule_position = i64::from_le_bytes(header[6..14].try_into()?);
+    Ok(Some(Page {
+        header_type: header[5],
+        granule_position: u64::try_from(granule_position).ok(),
+        serial: u32::from_le_bytes(header[14..18].try_into()?),
+        segments,
+        data,
+    }))
+}
+
+// Like read_exact, but returns false if the reader was already at the end
+fn read_fully(reader: &mut impl Read, buf: &mut [u8]) -> Result<bool> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..])? {
+            0 => return Ok(false),
+            n => read += n,
+        }
+    }
+    Ok(true)
+}
+
+// The checksum that Ogg uses: CRC-32 with polynomial 0x04c11db7, no reflection,
+// and an initial value of 0
+pub(super) fn crc32(crc: u32, bytes: &[u8]) -> u32 {
+    bytes.iter().fold(crc, |crc, byte| {
+        let mut crc = crc ^ ((*byte as u32) << 24);
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ 0x04c1_1db7
+            } else {
+                crc << 1
+            };
+        }
+        crc
+    })
+}
+
+// A packet, and the granule position of its page if it was the last packet to end there
+struct Packet {
+    data: Vec<u8>,
+    granule_position: Option<u64>,
+}
+
+// Put the segments of each page back together into the packets of the first
+// logical stream.  Chained or multiplexed streams are ignored.
+fn read_packets(reader: &mut impl Read) -> Result<Vec<Packet>> {
+    let mut packets = vec![];
+    let mut partial: Vec<u8> = vec![];
+    let mut serial = None;
+    while let Some(page) = read_page(reader)? {
+        if *serial.get_or_insert(page.serial) != page.serial {
+            continue;
+        }
+        if page.header_type & CONTINUED_PACKET == 0 {
+            partial.clear();
+        }
+        let mut offset = 0;
+        let mut ended = vec![];
+        for segment in &page.segments {
+            let len = *segment as usize;
+            partial.extend_from_slice(&page.data[offset..offset + len]);
+            offset += len;
+            // A segment shorter than 255 bytes ends a packet
+            if len < 255 {
+                ended.push(std::mem::take(&mut partial));
+            }
+        }
+        let last = ended.len().saturating_sub(1);
+        packets.extend(ended.into_iter().enumerate().map(|(i, data)| Packet {
+            data,
+            granule_position: if i == last {
+                page.granule_position
+            } else {
+                None
+            },
+        }));
+        if page.header_type & END_OF_STREAM != 0 {
+            break;
+        }
+    }
+    Ok(packets)
+}
+
+// The fields of the OpusHead packet that we need
+struct OpusHead {
+    channels: Channels,
+    pre_skip: usize,
+    input_sample_rate: u32,
+}
+
+fn parse_opus_head(packet: &[u8]) -> Result<OpusHead> {
+    if packet.len() < 19 || &packet[..8] != b"OpusHead" {
+        // The first packet of the other codecs starts with their name
+        let codec = match packet {
+            [1, b'v', b'o', b'r', b'b', b'i', b's', ..] => "Vorbis",
+            [0x7f, b'F', b'L', b'A', b'C', ..] => "FLAC",
+            [b'S', b'p', b'e', b'e', b'x', ..] => "Speex",
+            _ => "unknown",
+        };
+        return Err(UnsupportedFormat::new(format!("an Ogg file with {codec} audio")).into());
+    }
+    let channels = match packet[9] {
+        1 => Channels::Mono,
+        2 => Channels::Stereo,
+        n => {
+            return Err(
+                UnsupportedFormat::new(format!("an Ogg Opus file with {n} channels")).into(),
+            );
+        }
+    };
+    Ok(OpusHead {
+        channels,
+        pre_skip: u16::from_le_bytes([packet[10], packet[11]]) as usize,
+        input_sample_rate: u32::from_le_bytes(packet[12..16].try_into()?),
+    })
+}
+
+pub(super) struct OggOpusDecoder;
+
+impl AudioDecoder for OggOpusDecoder {
+    fn name(&self) -> &str {
+        "Ogg Opus"
+    }
+
+    fn sniff(&self, recording: &[u8]) -> bool {
+        recording.starts_with(MAGIC)
+    }
+
+    fn mime_types(&self) -> &[&str] {
+        &["audio/ogg", "application/ogg", "audio/opus"]
+    }
+
+    fn decode(&self, recording: &[u8]) -> Result<DecodedAudio> {
+        decode(recording)
+    }
+}
+
+// Decode an Ogg Opus file into interleaved samples at config::OPUS_DECODE_SAMPLE_RATE
+fn decode(mut reader: impl Read) -> Result<DecodedAudio> {
+    let packets = read_packets(&mut reader)?;
+    let Some(head) = packets.first() else {
+        bail!("This Ogg file is empty");
+    };
+    let head = parse_opus_head(&head.data)?;
+    let channel_count = head.channels as usize;
+    let mut decoder = OpusStream::new(head.channels)?;
+
+    // Skip the OpusHead and OpusTags packets
+    let mut pcm_data = vec![];
+    let mut last_granule_position = None;
+    for packet in packets.iter().skip(2) {
+        decoder.decode_packet(&packet.data, &mut pcm_data);
+        check_length(
+            pcm_data.len(),
+            channel_count,
+            config::OPUS_DECODE_SAMPLE_RATE,
+        )?;
+        last_granule_position = packet.granule_position.or(last_granule_position);
+    }
+
+    // Convert from 48 kHz granules to samples at the rate we decoded at
+    let to_samples = |granules: usize| {
+        granules * config::OPUS_DECODE_SAMPLE_RATE as usize / GRANULE_SAMPLE_RATE as usize
+    };
+    if let Some(end) = last_granule_position {
+        let end = to_samples(end as usize) * channel_count;
+        pcm_data.truncate(end);
+    }
+    let pre_skip = (to_samples(head.pre_skip) * channel_count).min(pcm_data.len());
+    pcm_data.drain(..pre_skip);
+    let dropped_packets = decoder.finish();
+
+    Ok(DecodedAudio {
+        interleaved: pcm_data,
+        channels: channel_count,
+        sample_rate: config::OPUS_DECODE_SAMPLE_RATE,
+        // 0 means that the encoder didn't say
+        original_sample_rate: match head.input_sample_rate {
+            0 => GRANULE_SAMPLE_RATE as f64,
+            rate => rate as f64,
+        },
+        dropped_packets,
+    })
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use std::{fs::File, io::Cursor};
+
+    use matroska_demuxer::{Frame, MatroskaFile};
+
+    use super::*;
+
+    // Make an Ogg Opus file out of the Opus packets in a webm recording, so that
+    // we have something like what Firefox records.  Each packet gets its own page.
+    pub(in crate::audio) fn webm_to_ogg(path: &str) -> Vec<u8> {
+        let mut webm = MatroskaFile::open(File::open(path).unwrap()).unwrap();
+        let audio = webm.tracks()[0].audio().unwrap();
+        let mut head = b"OpusHead\x01".to_vec();
+        head.push(audio.channels().get() as u8);
+        head.extend(312_u16.to_le_bytes());
+        head.extend((audio.sampling_frequency() as u32).to_le_bytes());
+        head.extend([0, 0, 0]);
+        let mut packets = vec![(head, 0), (b"OpusTags\0\0\0\0\0\0\0\0".to_vec(), 0)];
+
+        let decoder = opus::Decoder::new(48_000, Channels::Mono).unwrap();
+        let mut granule_position = 0;
+        let mut frame = Frame::default();
+        while webm.next_frame(&mut frame).unwrap() {
+            granule_position += decoder.get_nb_samples(&frame.data).unwrap() as u64;
+            packets.push((frame.data.clone(), granule_position));
+        }
+
+        let mut ogg = vec![];
+        let last = packets.len() - 1;
+        for (sequence, (packet, granule_position)) in packets.into_iter().enumerate() {
+            let header_type = match sequence {
+                0 => 0x02,
+                n if n == last => END_OF_STREAM,
+                _ => 0,
+            };
+            ogg.extend(page(
+                header_type,
+                granule_position,
+                sequence as u32,
+                &packet,
+            ));
+        }
+        ogg
+    }
+
+    fn page(header_type: u8, granule_position: u64, sequence: u32, packet: &[u8]) -> Vec<u8> {
+        let mut segments = vec![255; packet.len() / 255];
+        segments.push((packet.len() % 255) as u8);
+        page_with_segments(header_type, granule_position, sequence, &segments, packet)
+    }
+
+    fn page_with_segments(
+        header_type: u8,
+        granule_position: u64,
+        sequence: u32,
+        segments: &[u8],
+        data: &[u8],
+    ) -> Vec<u8> {
+        let mut page = MAGIC.to_vec();
+        page.extend([0, header_type]);
+        page.extend(granule_position.to_le_bytes());
+        page.extend(1_u32.to_le_bytes());
+        page.extend(sequence.to_le_bytes());
+        page.extend([0; 4]);
+        page.push(segments.len() as u8);
+        page.extend(segments);
+        page.extend(data);
+        let checksum = crc32(0, &page);
+        page[22..26].copy_from_slice(&checksum.to_le_bytes());
+        page
+    }
+
+    #[test]
+    fn it_computes_the_ogg_checksum() {
+        assert_eq!(crc32(0, b"123456789"), 0x89a1_897f);
+    }
+
+    #[test]
+    fn it_decodes_ogg_opus() {
+        let ogg = webm_to_ogg("./test_data/english/complete_book_of_cheese_mono.webm");
+        let decoded = decode(Cursor::new(&ogg)).unwrap();
+        assert_eq!(decoded.channels, 1);
+        assert_eq!(decoded.original_sample_rate, 24_000.0);
+        // The granule positions count 5 seconds at 48 kHz, minus the pre-skip
+        let seconds = decoded.interleaved.len() as f64 / decoded.sample_rate as f64;
+        assert!(seconds > 4.9 && seconds < 5.1, "{seconds} seconds");
+    }
+
+    #[test]
+    fn it_puts_packets_that_span_pages_back_together() {
+        let long_packet: Vec<u8> = (0..600).map(|i| i as u8).collect();
+        let mut ogg = page(0x02, 0, 0, &[1, 2, 3]);
+        // Two full segments, and no end of packet, so the packet continues on the next page
+        ogg.extend(page_with_segments(
+            0,
+            u64::MAX,
+            1,
+            &[255, 255],
+            &long_packet[..510],
+        ));
+        ogg.extend(page(CONTINUED_PACKET, 960, 2, &long_packet[510..]));
+
+        let packets = read_packets(&mut Cursor::new(ogg)).unwrap();
+        assert_eq!(packets.len(), 2);
+        assert_eq!(packets[1].data, long_packet);
+        assert_eq!(packets[1].granule_position, Some(960));
+    }
+
+    #[test]
+    fn it_stops_at_a_truncated_page() {
+        let ogg = webm_to_ogg("./test_data/english/complete_book_of_cheese_mono.webm");
+        let whole = read_packets(&mut Cursor::new(&ogg)).unwrap();
+        let truncated = read_packets(&mut Cursor::new(&ogg[..ogg.len() - 10])).unwrap();
+        assert_eq!(truncated.len(), whole.len() - 1);
+    }
+
+    #[test]
+    fn it_rejects_pages_with_a_bad_checksum() {
+        let mut ogg = page(0x02, 0, 0, b"OpusHead");
+        ogg[30] ^= 0xff;
+        assert!(read_packets(&mut Cursor::new(ogg)).is_err());
+    }
+
+    #[test]
+    fn it_names_the_codec_of_other_ogg_files() {
+        let ogg = page(0x02, 0, 0, b"\x01vorbis\0\0\0\0\x01\x44\xac\0\0");
+        let err = decode(Cursor::new(ogg)).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<UnsupportedFormat>(),
+            Some(&UnsupportedFormat::new("an Ogg file with Vorbis audio"))
+        );
+    }
+}
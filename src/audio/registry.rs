@@ -0,0 +1,228 @@
+// This module is responsible for choosing a decoder for a recording.  Each
+// format (webm, Ogg, MP4, ...) has an AudioDecoder, and the DecoderRegistry
+// asks each of them in turn whether they recognize the recording's first bytes.
+// If none of them do, it falls back to the MIME type that the client told us,
+// e.g. from MediaRecorder.mimeType or the websocket subprotocol.
+//
+// The first bytes are more trustworthy than a MIME type: browsers disagree about
+// what to call the same recording (Safari says audio/mp4 for what Chrome would
+// call audio/x-m4a), and clients often send a MIME type once for a whole session.
+//
+// When nothing matches, or a decoder recognizes the container but not the codec
+// inside it, the error is an UnsupportedFormat that says what we found.
+
+use std::{fmt, sync::Arc};
+
+use anyhow::Result;
+
+use super::{DecodedAudio, MatroskaDecoder, lab, mp4::Mp4AacDecoder, ogg::OggOpusDecoder};
+
+/// Turns recordings in one format into samples.  Implement this to teach a
+/// [`DecoderRegistry`] a new format.
+pub trait AudioDecoder: Send + Sync {
+    /// What this decoder handles, for errors and logs, e.g. "Ogg Opus"
+    fn name(&self) -> &str;
+    /// Whether the recording starts the way this format does
+    fn sniff(&self, recording: &[u8]) -> bool;
+    /// The MIME types of this format, without parameters, e.g. "audio/ogg"
+    fn mime_types(&self) -> &[&str];
+    /// Decode a whole recording.  If the container is right but the codec
+    /// inside it isn't, return an [`UnsupportedFormat`] error.
+    fn decode(&self, recording: &[u8]) -> Result<DecodedAudio>;
+}
+
+/// A recording that we can't decode, and what it seems to be instead
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnsupportedFormat {
+    /// e.g. "a Matroska file with A_AAC audio"
+    pub detected: String,
+}
+
+impl fmt::Display for UnsupportedFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Unsupported audio format: {}", self.detected)
+    }
+}
+
+impl std::error::Error for UnsupportedFormat {}
+
+impl UnsupportedFormat {
+    pub fn new(detected: impl Into<String>) -> UnsupportedFormat {
+        UnsupportedFormat {
+            detected: detected.into(),
+        }
+    }
+}
+
+/// The decoders that a [`Pipeline`](crate::Pipeline) can choose from.  The
+/// default registry knows webm (Opus or Vorbis), Ogg Opus, MP4 AAC, WAV, FLAC
+/// and MP3.
+#[derive(Clone)]
+pub struct DecoderRegistry {
+    decoders: Vec<Arc<dyn AudioDecoder>>,
+}
+
+impl Default for DecoderRegistry {
+    fn default() -> Self {
+        let mut registry = DecoderRegistry::empty();
+        registry.register(Arc::new(MatroskaDecoder));
+        registry.register(Arc::new(lab::LabDecoder(lab::Format::Mp3)));
+        registry.register(Arc::new(lab::LabDecoder(lab::Format::Flac)));
+        registry.register(Arc::new(lab::LabDecoder(lab::Format::Wav)));
+        registry.register(Arc::new(Mp4AacDecoder));
+        registry.register(Arc::new(OggOpusDecoder));
+        registry
+    }
+}
+
+impl DecoderRegistry {
+    /// A registry that can't decode anything, until you register some decoders
+    pub fn empty() -> DecoderRegistry {
+        DecoderRegistry { decoders: vec![] }
+    }
+
+    /// Decoders registered later are asked first, so that they can take over
+    /// a format from one of the defaults
+    pub fn register(&mut self, decoder: Arc<dyn AudioDecoder>) {
+        self.decoders.insert(0, decoder);
+    }
+
+    /// Whether any decoder handles this MIME type (parameters like `codecs` are ignored)
+    pub fn accepts_mime_type(&self, mime_type: &str) -> bool {
+        self.for_mime_type(mime_type).is_some()
+    }
+
+    /// Choose a decoder from the recording's first bytes, or else from the MIME
+    /// type that the client told us
+    pub fn detect(
+        &self,
+        recording: &[u8],
+        mime_type: Option<&str>,
+    ) -> Result<&dyn AudioDecoder, UnsupportedFormat> {
+        if let Some(decoder) = self.decoders.iter().find(|d| d.sniff(recording)) {
+            return Ok(decoder.as_ref());
+        }
+        if let Some(decoder) = mime_type.and_then(|mime_type| self.for_mime_type(mime_type)) {
+            return Ok(decoder);
+        }
+        let mut detected = match recording {
+            [] => "an empty recording".to_owned(),
+            _ => format!(
+                "a recording that starts with {}",
+                recording
+                    .iter()
+                    .take(8)
+                    .map(|byte| format!("{byte:02x}"))
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            ),
+        };
+        if let Some(mime_type) = mime_type {
+            detected.push_str(&format!(" (which the client called {mime_type})"));
+        }
+        Err(UnsupportedFormat::new(detected))
+    }
+
+    /// Choose a decoder and decode the recording with it
+    pub fn decode(&self, recording: &[u8], mime_type: Option<&str>) -> Result<DecodedAudio> {
+        let decoder = self.detect(recording, mime_type)?;
+        log::debug!("Decoding the recording as {}", decoder.name());
+        decoder.decode(recording)
+    }
+
+    fn for_mime_type(&self, mime_type: &str) -> Option<&dyn AudioDecoder> {
+        let essence = mime_type
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+        self.decoders
+            .iter()
+            .find(|decoder| decoder.mime_types().contains(&essence.as_str()))
+            .map(|decoder| decoder.as_ref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    // Decodes anything that starts with "TEST" into a second of silence
+    struct TestDecoder;
+
+    impl AudioDecoder for TestDecoder {
+        fn name(&self) -> &str {
+            "test"
+        }
+
+        fn sniff(&self, recording: &[u8]) -> bool {
+            recording.starts_with(b"TEST")
+        }
+
+        fn mime_types(&self) -> &[&str] {
+            &["audio/x-test"]
+        }
+
+        fn decode(&self, _recording: &[u8]) -> Result<DecodedAudio> {
+            Ok(DecodedAudio {
+                interleaved: vec![0.0; 16_000],
+                channels: 1,
+                sample_rate: 16_000,
+                original_sample_rate: 16_000.0,
+                dropped_packets: 0,
+            })
+        }
+    }
+
+    #[test]
+    fn it_detects_formats_from_their_first_bytes() {
+        let registry = DecoderRegistry::default();
+        for (path, name) in [
+            ("complete_book_of_cheese_mono.webm", "Matroska"),
+            ("complete_book_of_cheese_mono_aac.mp4", "MP4 AAC"),
+            ("complete_book_of_cheese_mono.flac", "FLAC"),
+            ("complete_book_of_cheese_mono.mp3", "MP3"),
+        ] {
+            let recording = fs::read(format!("./test_data/english/{path}")).unwrap();
+            // The first bytes win over a MIME type that doesn't match them
+            let decoder = registry.detect(&recording, Some("audio/ogg")).unwrap();
+            assert_eq!(decoder.name(), name);
+        }
+    }
+
+    #[test]
+    fn it_falls_back_to_the_mime_type() {
+        let mut registry = DecoderRegistry::default();
+        registry.register(Arc::new(TestDecoder));
+        let decoder = registry
+            .detect(b"no magic here", Some("Audio/X-Test; codecs=test"))
+            .unwrap();
+        assert_eq!(decoder.name(), "test");
+        assert!(registry.accepts_mime_type("audio/webm;codecs=opus"));
+        assert!(!registry.accepts_mime_type("audio/x-unknown"));
+
+        let decoded = registry.decode(b"TEST", None).unwrap();
+        assert_eq!(decoded.interleaved.len(), 16_000);
+    }
+
+    #[test]
+    fn it_says_what_it_found_when_it_cannot_decode_a_recording() {
+        let registry = DecoderRegistry::default();
+        assert_eq!(
+            registry
+                .detect(b"not a webm file", Some("audio/x-unknown"))
+                .err(),
+            Some(UnsupportedFormat::new(
+                "a recording that starts with 6e 6f 74 20 61 20 77 65 (which the client called audio/x-unknown)"
+            ))
+        );
+        assert_eq!(
+            registry.detect(b"", None).err().unwrap().to_string(),
+            "Unsupported audio format: an empty recording"
+        );
+        assert!(DecoderRegistry::empty().detect(b"TEST", None).is_err());
+    }
+}
@@ -0,0 +1,176 @@
+// This module is responsible for putting the samples decoded out of a webm file
+// at the time that their blocks say they start.  Decoders hand back packets one
+// after another, but the recording might not be: a paused MediaRecorder or a
+// dropped frame leaves a gap in the block timestamps, and a muxer can make
+// blocks that overlap.  We fill gaps with silence and drop samples that we
+// already have, so that segment timestamps line up with the original recording.
+//
+// The timestamps come from the client, though, so we don't trust them far: a
+// block that jumps further than config::MAX_WEBM_GAP_MS from the last one, or
+// past config::MAX_RECORDING_SECONDS, follows on from the last one instead.
+//
+// Positions on the timeline are counted in samples (per channel) at the rate
+// that the decoder produces.  The first samples of an Opus stream are the
+// encoder's warm up (pre-skip), so they have negative positions and are dropped.
+
+use crate::config;
+
+// The position of the first sample of a block.  Matroska says to subtract the
+// track's CodecDelay from block timestamps, and Opus says to skip pre_skip
+// samples from the start of the decoded audio.
+pub(super) struct Timeline {
+    channels: usize,
+    sample_rate: u32,
+    // Nanoseconds per block timestamp tick
+    timestamp_scale: u64,
+    codec_delay: u64,
+    pre_skip: usize,
+    // Where the samples we've placed so far end
+    end: i64,
+    // Block timestamps are rounded to the tick, so a gap or overlap of up to a
+    // tick is just rounding
+    tolerance: u64,
+    // The biggest gap or overlap that we believe, and the end of the longest
+    // recording that we accept
+    max_gap: u64,
+    max_end: i64,
+}
+
+impl Timeline {
+    pub(super) fn new(
+        channels: usize,
+        sample_rate: u32,
+        timestamp_scale: u64,
+        codec_delay: u64,
+        pre_skip: usize,
+    ) -> Timeline {
+        let tolerance = (timestamp_scale as u128 * sample_rate as u128).div_ceil(1_000_000_000);
+        Timeline {
+            channels,
+            sample_rate,
+            timestamp_scale,
+            codec_delay,
+            pre_skip,
+            end: -(pre_skip as i64),
+            tolerance: tolerance.clamp(1, u64::MAX as u128) as u64,
+            max_gap: config::MAX_WEBM_GAP_MS * sample_rate as u64 / 1_000,
+            max_end: (config::MAX_RECORDING_SECONDS * sample_rate as usize) as i64,
+        }
+    }
+
+    // Where the first decoded sample of a block with this timestamp belongs, or
+    // None if it's too far from the start to count in samples
+    fn position(&self, timestamp: u64) -> Option<i64> {
+        let nanoseconds = (timestamp as i128)
+            .checked_mul(self.timestamp_scale as i128)?
+            .checked_sub(self.codec_delay as i128)?;
+        let samples = nanoseconds.checked_mul(self.sample_rate as i128)? / 1_000_000_000;
+        i64::try_from(samples - self.pre_skip as i128).ok()
+    }
+
+    // Put the interleaved samples decoded from a block onto the end of
+    // pcm_data.  Frames that were laced into one block share its timestamp,
+    // so pass None for all but the first of them, to follow on from the last.
+    pub(super) fn place(
+        &mut self,
+        timestamp: Option<u64>,
+        decoded: &[f32],
+        pcm_data: &mut Vec<f32>,
+    ) {
+        let start = match timestamp.map(|timestamp| self.position(timestamp)) {
+            None => self.end,
+            Some(Some(start)) if start.abs_diff(self.end) <= self.tolerance => self.end,
+            Some(Some(start))
+                if start.abs_diff(self.end) <= self.max_gap && start <= self.max_end =>
+            {
+                log::debug!(
+                    "A webm block starts {} samples from where the last one ended",
+                    start - self.end
+                );
+                start
+            }
+            Some(_) => {
+                log::warn!(
+                    "A webm block's timestamp ({timestamp:?}) is too far from the last one, so we follow on from it"
+                );
+                self.end
+            }
+        };
+        let written = (pcm_data.len() / self.channels) as i64;
+        if start > written {
+            pcm_data.resize((start as usize) * self.channels, 0.0);
+        }
+        let skip = ((written - start).max(0) as usize * self.channels).min(decoded.len());
+        pcm_data.extend_from_slice(&decoded[skip..]);
+        self.end = start + (decoded.len() / self.channels) as i64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // One sample per millisecond makes the arithmetic easy to follow
+    fn timeline(codec_delay_ms: u64, pre_skip: usize) -> Timeline {
+        Timeline::new(1, 1_000, 1_000_000, codec_delay_ms * 1_000_000, pre_skip)
+    }
+
+    #[test]
+    fn it_drops_the_pre_skip() {
+        let mut timeline = timeline(2, 2);
+        let mut pcm_data = vec![];
+        timeline.place(Some(2), &[1.0, 2.0, 3.0, 4.0], &mut pcm_data);
+        timeline.place(Some(6), &[5.0, 6.0], &mut pcm_data);
+        assert_eq!(pcm_data, vec![3.0, 4.0, 5.0, 6.0]);
+    }
+
+    #[test]
+    fn it_fills_gaps_with_silence() {
+        let mut timeline = Timeline::new(2, 1_000, 1_000_000, 0, 0);
+        let mut pcm_data = vec![];
+        timeline.place(Some(0), &[1.0, -1.0, 2.0, -2.0], &mut pcm_data);
+        timeline.place(Some(5), &[3.0, -3.0], &mut pcm_data);
+        assert_eq!(
+            pcm_data,
+            vec![
+                1.0, -1.0, 2.0, -2.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 3.0, -3.0
+            ]
+        );
+    }
+
+    #[test]
+    fn it_drops_samples_that_overlap() {
+        let mut timeline = timeline(0, 0);
+        let mut pcm_data = vec![];
+        timeline.place(Some(0), &[1.0, 2.0, 3.0, 4.0, 5.0], &mut pcm_data);
+        timeline.place(Some(2), &[6.0, 7.0, 8.0, 9.0, 10.0], &mut pcm_data);
+        assert_eq!(pcm_data, vec![1.0, 2.0, 3.0, 4.0, 5.0, 9.0, 10.0]);
+    }
+
+    #[test]
+    fn it_ignores_rounding_and_follows_on_from_laced_frames() {
+        let mut timeline = timeline(0, 0);
+        let mut pcm_data = vec![];
+        timeline.place(Some(0), &[1.0, 2.0, 3.0], &mut pcm_data);
+        // A tick late, and then a frame laced into the same block
+        timeline.place(Some(4), &[4.0, 5.0], &mut pcm_data);
+        timeline.place(None, &[6.0], &mut pcm_data);
+        assert_eq!(pcm_data, vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+    }
+
+    #[test]
+    fn it_follows_on_from_blocks_that_jump_too_far() {
+        let mut timeline = timeline(0, 0);
+        let mut pcm_data = vec![];
+        timeline.place(Some(0), &[1.0, 2.0], &mut pcm_data);
+        // An hour later, and then back to where we were
+        timeline.place(Some(3_600_000), &[3.0, 4.0], &mut pcm_data);
+        timeline.place(Some(4), &[5.0], &mut pcm_data);
+        // And timestamps too big to count in samples
+        timeline.place(Some(u64::MAX), &[6.0], &mut pcm_data);
+        assert_eq!(pcm_data, vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+
+        let mut timeline = Timeline::new(1, 48_000, u64::MAX, u64::MAX, 312);
+        timeline.place(Some(u64::MAX), &[1.0], &mut pcm_data);
+    }
+}
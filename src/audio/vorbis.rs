@@ -0,0 +1,73 @@
+// This module is responsible for decoding Vorbis audio out of a webm file,
+// which is what older recorders (and some of our archived clips) made before
+// browsers switched to Opus.  The symphonia crate does the decoding.
+//
+// A Vorbis decoder needs three header packets before it can decode any audio:
+// identification, comment and setup.  Matroska doesn't store those as frames,
+// it packs them into the track's CodecPrivate with Xiph lacing, i.e. a byte
+// for the number of packets minus one, the sizes of all but the last packet,
+// and then the packets.  The symphonia decoder understands that layout as is.
+//
+// The identification header says what rate and how many channels the audio
+// was encoded with.  We trust it over the Matroska track, which can leave out
+// its SamplingFrequency (which then defaults to 8 kHz).
+
+use anyhow::{Context, Result};
+use symphonia::{
+    core::{
+        codecs::audio::{
+            AudioCodecParameters, AudioDecoder, AudioDecoderOptions, well_known::CODEC_ID_VORBIS,
+        },
+        packet::Packet,
+        units::{Duration, Timestamp},
+    },
+    default::codecs::VorbisDecoder,
+};
+
+pub(super) struct Vorbis {
+    decoder: VorbisDecoder,
+    decoded: Vec<f32>,
+    // From the identification header
+    pub(super) sample_rate: u32,
+    pub(super) channels: usize,
+}
+
+impl Vorbis {
+    // Set up a decoder from a Matroska track's CodecPrivate
+    pub(super) fn new(codec_private: &[u8]) -> Result<Vorbis> {
+        let mut params = AudioCodecParameters::new();
+        params
+            .for_codec(CODEC_ID_VORBIS)
+            .with_extra_data(codec_private.into());
+        let decoder = VorbisDecoder::try_new(&params, &AudioDecoderOptions::default())
+            .context("Could not read the Vorbis headers of this file")?;
+        // The decoder's buffer is set up from the identification header
+        let spec = decoder.last_decoded().spec().clone();
+        Ok(Vorbis {
+            decoder,
+            decoded: vec![],
+            sample_rate: spec.rate(),
+            channels: spec.channels().count(),
+        })
+    }
+
+    // Decode a Vorbis packet onto the end of pcm_data, with the channels interleaved
+    pub(super) fn decode_packet(&mut self, packet: &[u8], pcm_data: &mut Vec<f32>) -> Result<()> {
+        let packet = Packet::new(0, Timestamp::ZERO, Duration::ZERO, packet);
+        let buffer = self.decoder.decode(&packet)?;
+        buffer.copy_to_vec_interleaved(&mut self.decoded);
+        pcm_data.extend_from_slice(&self.decoded);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_errors_without_the_header_packets() {
+        assert!(Vorbis::new(&[]).is_err());
+        assert!(Vorbis::new(&[2, 30, 10, 1, 2, 3]).is_err());
+    }
+}
@@ -2,11 +2,8 @@
 // Audio decoding settings
 // -----------------------
 
-// Sample rate must be 8, 12, 16, 24, or 48 kHz.  The libopus documentation recommends 48.
-// The whisper paper mentioned that they re-sampled their audio to 16 MHz in training.
-// Interestingly: when using the symphonia crate's matroska demuxer, a lower sample rate provided
-// more accurate results.  With the matroska-demuxer crate, a higher sample rate seems to work better.
-// In either case, if you get it too high, the transcriptions are just "... ... ..."
+// libopus can decode at 8, 12, 16, 24, or 48 kHz, whatever rate the recording was made at.
+// We decode at 48 kHz, as the libopus documentation recommends, and then resample.
 //
 // Note that the sample rate of a file from the browser may not be one of the rates supported
 // by libopus.  When using the browser's MediaRecorder API, you can pass in a custom sample
@@ -14,23 +11,102 @@
 // See:
 //  * https://opus-codec.org/docs/opus_api-1.5.pdf
 //  * https://developer.mozilla.org/en-US/docs/Web/API/MediaRecorder/MediaRecorder#audiobitspersecond
-//  * https://arxiv.org/pdf/2212.04356 (the whisper paper, their process is described on page 3)
+pub const OPUS_DECODE_SAMPLE_RATE: u32 = 48_000;
+
+// The sample rate that we resample recordings to before extracting features.
+// The whisper paper mentions that they re-sampled their audio to 16 kHz in training
+// (https://arxiv.org/pdf/2212.04356, page 3), so that is what the model expects.
+//
+// Even so, we resample to 12 kHz and hand the samples to whisper as if they were
+// 16 kHz, because (empirically) that gave better transcriptions than decoding at
+// a higher rate.  Now that we resample properly, 16 kHz may do better; before
+// switching, compare the two on the test recordings with:
+//
+//   cargo test --release compare_sample_rates -- --ignored --nocapture
 pub const AUDIO_DECODE_SAMPLE_RATE: u32 = 12_000;
 
+// The sample rates and channel counts that we accept in a decoded file.  Others
+// are more likely a damaged header than a real recording, and resampling from
+// a rate that's far from ours is slow.
+pub const MIN_DECODED_SAMPLE_RATE: u32 = 8_000;
+pub const MAX_DECODED_SAMPLE_RATE: u32 = 192_000;
+pub const MAX_DECODED_CHANNELS: usize = 8;
+
+// What we accept from clients that stream raw PCM frames instead of a recording,
+// e.g. from an AudioWorklet, which runs at the rate of the patron's sound card.
+pub const MIN_RAW_PCM_SAMPLE_RATE: u32 = 8_000;
+pub const MAX_RAW_PCM_SAMPLE_RATE: u32 = 192_000;
+pub const MAX_RAW_PCM_CHANNELS: usize = 8;
+
+// We hold on to raw PCM frames until the client ends the recording, so that a
+// client can't fill up our memory, we stop listening after this many seconds,
+// or this many bytes (about 3 minutes of 48 kHz mono f32le), whichever is first.
+// The client chooses the format, so the seconds alone don't limit the bytes.
+pub const MAX_RAW_PCM_SECONDS: usize = 120;
+pub const MAX_RAW_PCM_BYTES: usize = 32 * 1024 * 1024;
+
+// Webm blocks say when their samples start, and we fill the gaps between them
+// with silence.  A gap longer than this is more likely a damaged timestamp than
+// a paused recording, so we carry on from the last block instead.  Nor do we
+// fill in past the end of the longest recording that we expect.
+pub const MAX_WEBM_GAP_MS: u64 = 500;
+
+// The longest recording that we decode.  A small file of compressed silence can
+// decode to hours of audio, so decoders stop with an error once they pass this.
+pub const MAX_RECORDING_SECONDS: usize = 10 * 60;
+
 // ---------------------------------
 // HuggingFace repository settings
 // ---------------------------------
 pub const REPO_ID: &str = "Demonthos/candle-quantized-whisper-large-v3-turbo";
 
-// WEIGHTS_FILENAME should be a gguf file, since they are optimized for inference
+// MODEL_FILENAME should usually be a gguf file, since they are optimized for inference
 // See: https://huggingface.co/docs/hub/gguf#gguf
+//
+// It can also be a full precision safetensors file, e.g. to compare accuracy against
+// the quantized model.  In that case, use a repo like "openai/whisper-large-v3-turbo"
+// and "model.safetensors".  We tell the two formats apart by looking at the file.
 pub const MODEL_FILENAME: &str = "model.gguf";
 pub const TOKENIZER_FILENAME: &str = "tokenizer.json";
 pub const MODEL_CONFIG_FILENAME: &str = "config.json";
 
+// A JSON file listing the models that this deployment hosts, and when to use each one,
+// see model_registry.rs.  If the file does not exist, we host the model described above.
+pub const MODELS_FILE: &str = "models.json";
+
+// POST /admin/reload reloads the models in MODELS_FILE.  It has its own port, which
+// only this machine can reach, so that patrons who can reach the websocket can't use it.
+pub const ADMIN_PORT: u16 = 7026;
+
 // -------------------
 // Inference settings
 // -------------------
 
 // Seed to help provide randomness in a weighted index
 pub const SEED: u64 = 299792458;
+
+// A JSON file with this deployment's defaults for decoding, see decoding_options.rs.
+// If the file does not exist, we use the defaults from the whisper paper.
+pub const DECODING_OPTIONS_FILE: &str = "decoding_options.json";
+
+// When the same phrase of up to MAX_REPEATED_PHRASE_LENGTH words shows up more than
+// MAX_CONSECUTIVE_REPEATS times in a row, the model is probably stuck in a loop.
+// We retry at a higher temperature, and discard the segment if that doesn't help.
+pub const MAX_REPEATED_PHRASE_LENGTH: usize = 4;
+pub const MAX_CONSECUTIVE_REPEATS: usize = 2;
+
+// Below this average word confidence, we tell the client that we aren't sure
+// what the patron said, so that they can ask the patron to try again.
+pub const MIN_CONFIDENCE: f64 = 0.5;
+
+// When we can't recognize anything and the recording's signal-to-noise ratio is below
+// this many decibels, we tell the client that there was too much background noise.
+pub const MIN_SNR_DB: f64 = 6.0;
+
+// A text file with one catalog phrase per line (e.g. author names) that the model
+// should be nudged toward.  Changes to the file are picked up without a restart.
+pub const HOTWORDS_FILE: &str = "hotwords.txt";
+
+// How much to add to the logit of a token that continues one of the hotwords.
+// Too high, and the model will insert hotwords that were never spoken.
+pub const HOTWORD_BOOST: f32 = 2.0;
@@ -0,0 +1,249 @@
+// This module describes the knobs that control how the decoder
+// turns audio features into text.
+//
+// Each deployment can set its own defaults in config::DECODING_OPTIONS_FILE,
+// a JSON file with any of the fields of DecodingOptions, e.g.
+//
+//   {"temperatures": [0.0, 0.4, 0.8], "max_tokens": 64}
+//
+// Clients can then override them per session or per request (see the session module).
+
+use std::{fs, path::Path, sync::OnceLock};
+
+use anyhow::{Context, Result, bail};
+use candle_transformers::models::whisper::{
+    COMPRESSION_RATIO_THRESHOLD, LOGPROB_THRESHOLD, NO_SPEECH_THRESHOLD, TEMPERATURES,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{audio::ChannelSelection, config, grammar::Grammar};
+
+static DEPLOYMENT_DEFAULTS: OnceLock<DecodingOptions> = OnceLock::new();
+
+/// The knobs that control how a recording is transcribed.  The defaults
+/// come from the whisper paper.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DecodingOptions {
+    /// The name of the model to use, see the model_registry module.  If not set,
+    /// the model is chosen by the registry's routing rules.
+    pub model: Option<String>,
+    /// The language the patron is probably speaking (e.g. "en"), to help choose a model
+    pub language: Option<String>,
+    /// How to turn a stereo recording into mono: "downmix", "loudest", or a channel number
+    pub channel: ChannelSelection,
+    /// Temperatures to try in order, falling back to the next one when a
+    /// transcription looks unreliable.  0 means always take the likeliest token.
+    pub temperatures: Vec<f64>,
+    /// Fall back to the next temperature if the average token log probability is lower than this
+    pub logprob_threshold: f64,
+    /// Treat a segment as silent if the probability of the no speech token is higher than this
+    pub no_speech_threshold: f64,
+    /// Fall back to the next temperature if the text compresses better than this
+    pub compression_ratio_threshold: f64,
+    /// The most tokens to generate for each 30 second segment.  Defaults to half the model's context.
+    pub max_tokens: Option<usize>,
+    /// Seed for sampling at temperatures above 0
+    pub seed: u64,
+    /// Token ids that the model may never produce, in addition to those in the model's config
+    pub suppress_tokens: Vec<u32>,
+    /// Text to prime the model with, e.g. domain vocabulary
+    pub prompt: Option<String>,
+    /// A pattern that the transcription must match, see the grammar module
+    pub grammar: Option<String>,
+    /// Tell the client the transcription is low confidence if the average word
+    /// confidence is below this, see the transcript module
+    pub min_confidence: f64,
+    /// Tell the client the recording was too noisy if we couldn't recognize anything
+    /// and the estimated signal-to-noise ratio (in decibels) is below this
+    pub min_snr_db: f64,
+}
+
+impl Default for DecodingOptions {
+    fn default() -> Self {
+        DecodingOptions {
+            model: None,
+            language: None,
+            channel: ChannelSelection::default(),
+            temperatures: TEMPERATURES.to_vec(),
+            logprob_threshold: LOGPROB_THRESHOLD,
+            no_speech_threshold: NO_SPEECH_THRESHOLD,
+            compression_ratio_threshold: COMPRESSION_RATIO_THRESHOLD,
+            max_tokens: None,
+            seed: config::SEED,
+            suppress_tokens: vec![],
+            prompt: None,
+            grammar: None,
+            min_confidence: config::MIN_CONFIDENCE,
+            min_snr_db: config::MIN_SNR_DB,
+        }
+    }
+}
+
+impl DecodingOptions {
+    /// The defaults for this deployment, read from `decoding_options.json` if it
+    /// exists.  Errors if the file is malformed or the options are invalid.
+    pub fn deployment_defaults() -> Result<&'static DecodingOptions> {
+        if let Some(defaults) = DEPLOYMENT_DEFAULTS.get() {
+            return Ok(defaults);
+        }
+        let defaults = load(Path::new(config::DECODING_OPTIONS_FILE))?;
+        Ok(DEPLOYMENT_DEFAULTS.get_or_init(|| defaults))
+    }
+
+    /// Explains what is wrong with the options, if anything
+    pub fn validate(&self) -> Result<()> {
+        if self.temperatures.is_empty() {
+            bail!("temperatures must have at least one temperature");
+        }
+        if let Some(t) = self
+            .temperatures
+            .iter()
+            .find(|t| !t.is_finite() || **t < 0.0)
+        {
+            bail!("temperatures must be zero or positive numbers, but got {t}");
+        }
+        if !self.logprob_threshold.is_finite() || self.logprob_threshold > 0.0 {
+            bail!(
+                "logprob_threshold must be zero or a negative number, but got {}",
+                self.logprob_threshold
+            );
+        }
+        if !(0.0..=1.0).contains(&self.no_speech_threshold) {
+            bail!(
+                "no_speech_threshold must be between 0 and 1, but got {}",
+                self.no_speech_threshold
+            );
+        }
+        if self.compression_ratio_threshold.is_nan() || self.compression_ratio_threshold <= 0.0 {
+            bail!(
+                "compression_ratio_threshold must be a positive number, but got {}",
+                self.compression_ratio_threshold
+            );
+        }
+        if !(0.0..=1.0).contains(&self.min_confidence) {
+            bail!(
+                "min_confidence must be between 0 and 1, but got {}",
+                self.min_confidence
+            );
+        }
+        if !self.min_snr_db.is_finite() {
+            bail!("min_snr_db must be a number, but got {}", self.min_snr_db);
+        }
+        if self.max_tokens == Some(0) {
+            bail!("max_tokens must be at least 1");
+        }
+        if let Some(pattern) = &self.grammar {
+            Grammar::new(pattern)?;
+        }
+        Ok(())
+    }
+
+    // The number of tokens to generate per segment, given the model's context size
+    pub(crate) fn sample_len(&self, max_target_positions: usize) -> Result<usize> {
+        let limit = max_target_positions / 2;
+        match self.max_tokens {
+            None => Ok(limit),
+            Some(max_tokens) if max_tokens <= limit => Ok(max_tokens),
+            Some(max_tokens) => bail!("max_tokens must be at most {limit}, but got {max_tokens}"),
+        }
+    }
+}
+
+fn load(path: &Path) -> Result<DecodingOptions> {
+    if !path.exists() {
+        return Ok(DecodingOptions::default());
+    }
+    let options: DecodingOptions = serde_json::from_str(&fs::read_to_string(path)?)
+        .with_context(|| format!("Could not read decoding options from {path:?}"))?;
+    options
+        .validate()
+        .with_context(|| format!("Invalid decoding options in {path:?}"))?;
+    Ok(options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_uses_whisper_defaults() {
+        let options = DecodingOptions::default();
+        assert_eq!(options.temperatures, TEMPERATURES.to_vec());
+        assert!(options.validate().is_ok());
+        assert_eq!(options.sample_len(448).unwrap(), 224);
+    }
+
+    #[test]
+    fn it_fills_in_missing_fields_with_defaults() {
+        let options: DecodingOptions =
+            serde_json::from_str(r#"{"temperatures": [0.0, 0.5], "max_tokens": 32}"#).unwrap();
+        assert_eq!(options.temperatures, vec![0.0, 0.5]);
+        assert_eq!(options.logprob_threshold, LOGPROB_THRESHOLD);
+        assert_eq!(options.sample_len(448).unwrap(), 32);
+    }
+
+    #[test]
+    fn it_reads_the_channel_selection() {
+        let channel = |json: &str| {
+            serde_json::from_str::<DecodingOptions>(json).map(|options| options.channel)
+        };
+        assert_eq!(channel("{}").unwrap(), ChannelSelection::Downmix);
+        assert_eq!(
+            channel(r#"{"channel": "loudest"}"#).unwrap(),
+            ChannelSelection::Loudest
+        );
+        assert_eq!(
+            channel(r#"{"channel": 1}"#).unwrap(),
+            ChannelSelection::Channel(1)
+        );
+        assert!(channel(r#"{"channel": "left"}"#).is_err());
+    }
+
+    #[test]
+    fn it_explains_invalid_options() {
+        let invalid = [
+            (r#"{"temperatures": []}"#, "temperatures must have"),
+            (r#"{"temperatures": [0.0, -1.0]}"#, "but got -1"),
+            (r#"{"logprob_threshold": 1.0}"#, "logprob_threshold"),
+            (r#"{"no_speech_threshold": 2.0}"#, "no_speech_threshold"),
+            (
+                r#"{"compression_ratio_threshold": 0}"#,
+                "compression_ratio_threshold",
+            ),
+            (r#"{"max_tokens": 0}"#, "max_tokens"),
+            (r#"{"min_confidence": 1.5}"#, "min_confidence"),
+            (r#"{"grammar": "(next"}"#, "grammar"),
+        ];
+        for (json, message) in invalid {
+            let options: DecodingOptions = serde_json::from_str(json).unwrap();
+            let err = options.validate().unwrap_err().to_string();
+            assert!(err.contains(message), "{err:?} should mention {message:?}");
+        }
+    }
+
+    #[test]
+    fn it_limits_max_tokens_to_half_the_context() {
+        let options = DecodingOptions {
+            max_tokens: Some(500),
+            ..Default::default()
+        };
+        assert!(options.sample_len(448).is_err());
+    }
+
+    #[test]
+    fn it_loads_defaults_when_there_is_no_file() {
+        let options = load(Path::new("./test_data/no_such_file.json")).unwrap();
+        assert_eq!(options, DecodingOptions::default());
+    }
+
+    #[test]
+    fn it_errors_on_a_malformed_file() {
+        let path = std::env::temp_dir().join("voice_search_server_decoding_options_test.json");
+        fs::write(&path, r#"{"max_tokens": "#).unwrap();
+        assert!(load(&path).is_err());
+        fs::write(&path, r#"{"max_tokens": 0}"#).unwrap();
+        assert!(load(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -7,10 +7,9 @@ use candle_transformers::models::whisper::{Config, audio};
 
 use crate::whisper_repo::WhisperRepo;
 
-pub fn extract_features(samples: Vec<f32>) -> Result<Vec<f32>, anyhow::Error> {
-    let config: Config = serde_json::from_str(
-        &std::fs::read_to_string(&WhisperRepo::get().config_file).unwrap(),
-    )?;
+pub fn extract_features(samples: Vec<f32>, repo: &WhisperRepo) -> Result<Vec<f32>, anyhow::Error> {
+    let config: Config =
+        serde_json::from_str(&std::fs::read_to_string(&repo.config_file).unwrap())?;
 
     let mel_bytes = match config.num_mel_bins {
         80 => include_bytes!("./melfilters.bytes").as_slice(),
@@ -26,6 +25,7 @@ pub fn extract_features(samples: Vec<f32>) -> Result<Vec<f32>, anyhow::Error> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::model_registry::ModelRegistry;
 
     #[test]
     fn it_can_extract_features() {
@@ -300,6 +300,18 @@ mod tests {
 
         // A small set of samples becomes a huge number of features!
         assert!(original.len() < 300);
-        assert!(extract_features(original).unwrap().len() > 300_000);
+        assert!(
+            extract_features(
+                original,
+                ModelRegistry::get()
+                    .unwrap()
+                    .default_model()
+                    .try_repo()
+                    .unwrap()
+            )
+            .unwrap()
+            .len()
+                > 300_000
+        );
     }
 }
@@ -0,0 +1,570 @@
+// This module is responsible for constraining the transcription
+// to text that matches a pattern, for fielded or command-style
+// input like call numbers, ISBNs, or "next page".
+//
+// Patterns use a small subset of regular expression syntax:
+//
+//   * literal characters, and `\` to escape special characters
+//   * `.` for any character, and the classes `\d`, `\w`, and `\s`
+//   * character sets like `[0-9X]` and `[^ ]`
+//   * groups `( )` and alternation `|`
+//   * the quantifiers `*`, `+`, `?`, `{n}`, `{n,}`, and `{n,m}`
+//
+// Matching is case-insensitive and always anchored to the whole transcription,
+// although leading whitespace is ignored since whisper tends to begin with a space.
+//
+// The pattern is compiled into a nondeterministic finite automaton (see
+// https://swtch.com/~rsc/regexp/regexp1.html), and at each step of decoding,
+// any token whose text would take the automaton into a dead end is masked out.
+// The decoder steps the automaton along with each token that it chooses, and
+// the mask for each set of states is worked out once and then cached, since
+// checking it against the whole vocabulary is the slow part.
+
+use std::collections::HashMap;
+
+use anyhow::{Result, anyhow, bail};
+use tokenizers::Tokenizer;
+
+// Patterns come from clients, so we limit how big their automatons can get.
+// Repeats copy the states of what they repeat, so nested repeats like
+// `((a{100}){100}){100}` multiply, and we limit the total as well as each repeat.
+// Every state costs time for each token in the vocabulary, so the total is small.
+const MAX_REPETITIONS: u32 = 100;
+const MAX_STATES: usize = 500;
+
+// Each mask has an entry for every token in the vocabulary, so we only keep this many
+const MAX_CACHED_MASKS: usize = 64;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Ast {
+    Empty,
+    Class(CharClass),
+    Concat(Vec<Ast>),
+    Alternate(Vec<Ast>),
+    Repeat(Box<Ast>, u32, Option<u32>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct CharClass {
+    ranges: Vec<(char, char)>,
+    negated: bool,
+}
+
+impl CharClass {
+    fn single(c: char) -> CharClass {
+        CharClass {
+            ranges: vec![(c, c)],
+            negated: false,
+        }
+    }
+
+    fn any() -> CharClass {
+        CharClass {
+            ranges: vec![],
+            negated: true,
+        }
+    }
+
+    fn matches(&self, c: char) -> bool {
+        let in_ranges = |c: char| self.ranges.iter().any(|&(lo, hi)| lo <= c && c <= hi);
+        let found =
+            in_ranges(c) || c.to_lowercase().any(in_ranges) || c.to_uppercase().any(in_ranges);
+        found != self.negated
+    }
+}
+
+struct Parser<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+}
+
+impl Parser<'_> {
+    fn parse(pattern: &str) -> Result<Ast> {
+        let mut parser = Parser {
+            chars: pattern.chars().peekable(),
+        };
+        let ast = parser.alternation()?;
+        match parser.chars.next() {
+            None => Ok(ast),
+            Some(c) => bail!("unexpected '{c}' in grammar {pattern:?}"),
+        }
+    }
+
+    fn alternation(&mut self) -> Result<Ast> {
+        let mut branches = vec![self.concatenation()?];
+        while self.chars.next_if_eq(&'|').is_some() {
+            branches.push(self.concatenation()?);
+        }
+        Ok(if branches.len() == 1 {
+            branches.pop().unwrap()
+        } else {
+            Ast::Alternate(branches)
+        })
+    }
+
+    fn concatenation(&mut self) -> Result<Ast> {
+        let mut items = vec![];
+        while let Some(&c) = self.chars.peek() {
+            if c == '|' || c == ')' {
+                break;
+            }
+            let atom = self.atom()?;
+            items.push(self.quantifiers(atom)?);
+        }
+        Ok(match items.len() {
+            0 => Ast::Empty,
+            1 => items.pop().unwrap(),
+            _ => Ast::Concat(items),
+        })
+    }
+
+    fn quantifiers(&mut self, mut atom: Ast) -> Result<Ast> {
+        while let Some(&c) = self.chars.peek() {
+            let (min, max) = match c {
+                '*' => (0, None),
+                '+' => (1, None),
+                '?' => (0, Some(1)),
+                '{' => {
+                    self.chars.next();
+                    self.bounds()?
+                }
+                _ => break,
+            };
+            if c != '{' {
+                self.chars.next();
+            }
+            atom = Ast::Repeat(Box::new(atom), min, max);
+        }
+        Ok(atom)
+    }
+
+    // The inside of {n}, {n,}, or {n,m}
+    fn bounds(&mut self) -> Result<(u32, Option<u32>)> {
+        let mut inside = String::new();
+        loop {
+            match self.chars.next() {
+                Some('}') => break,
+                Some(c) => inside.push(c),
+                None => bail!("unclosed '{{' in grammar"),
+            }
+        }
+        let number = |s: &str| {
+            s.trim()
+                .parse::<u32>()
+                .map_err(|_| anyhow!("invalid repetition {{{inside}}} in grammar"))
+        };
+        let (min, max) = match inside.split_once(',') {
+            None => (number(&inside)?, Some(number(&inside)?)),
+            Some((min, "")) => (number(min)?, None),
+            Some((min, max)) => (number(min)?, Some(number(max)?)),
+        };
+        if max.is_some_and(|max| max < min) || min.max(max.unwrap_or(0)) > MAX_REPETITIONS {
+            bail!("invalid repetition {{{inside}}} in grammar");
+        }
+        Ok((min, max))
+    }
+
+    fn atom(&mut self) -> Result<Ast> {
+        match self.chars.next() {
+            Some('(') => {
+                let inner = self.alternation()?;
+                match self.chars.next() {
+                    Some(')') => Ok(inner),
+                    _ => bail!("unclosed '(' in grammar"),
+                }
+            }
+            Some('[') => Ok(Ast::Class(self.set()?)),
+            Some('.') => Ok(Ast::Class(CharClass::any())),
+            Some('\\') => Ok(Ast::Class(self.escape()?)),
+            Some(c @ ('*' | '+' | '?' | '{')) => bail!("nothing to repeat before '{c}' in grammar"),
+            Some(c) => Ok(Ast::Class(CharClass::single(c))),
+            None => bail!("unexpected end of grammar"),
+        }
+    }
+
+    fn escape(&mut self) -> Result<CharClass> {
+        let ranges = match self.chars.next() {
+            Some('d') => vec![('0', '9')],
+            Some('w') => vec![('a', 'z'), ('A', 'Z'), ('0', '9'), ('_', '_')],
+            Some('s') => vec![(' ', ' '), ('\t', '\t'), ('\n', '\n'), ('\r', '\r')],
+            Some(c) => vec![(c, c)],
+            None => bail!("unexpected end of grammar after '\\'"),
+        };
+        Ok(CharClass {
+            ranges,
+            negated: false,
+        })
+    }
+
+    // The inside of [...]
+    fn set(&mut self) -> Result<CharClass> {
+        let negated = self.chars.next_if_eq(&'^').is_some();
+        let mut ranges = vec![];
+        loop {
+            let lo = match self.chars.next() {
+                Some(']') if !ranges.is_empty() => break,
+                Some('\\') => {
+                    ranges.extend(self.escape()?.ranges);
+                    continue;
+                }
+                Some(c) => c,
+                None => bail!("unclosed '[' in grammar"),
+            };
+            if self.chars.next_if_eq(&'-').is_some() {
+                match self.chars.next() {
+                    Some(']') => {
+                        ranges.push((lo, lo));
+                        ranges.push(('-', '-'));
+                        break;
+                    }
+                    Some(hi) if hi >= lo => ranges.push((lo, hi)),
+                    Some(hi) => bail!("invalid range {lo}-{hi} in grammar"),
+                    None => bail!("unclosed '[' in grammar"),
+                }
+            } else {
+                ranges.push((lo, lo));
+            }
+        }
+        Ok(CharClass { ranges, negated })
+    }
+}
+
+#[derive(Debug, Clone)]
+enum State {
+    Char(CharClass, usize),
+    Split(Vec<usize>),
+    Match,
+}
+
+#[derive(Debug, Clone)]
+pub struct Grammar {
+    states: Vec<State>,
+    start: usize,
+}
+
+// The set of automaton states that the text so far could have led to
+pub type Position = Vec<usize>;
+
+impl Grammar {
+    pub fn new(pattern: &str) -> Result<Grammar> {
+        let ast = Parser::parse(pattern)?;
+        // Ignore leading whitespace
+        let whitespace = Ast::Repeat(Box::new(Ast::Class(CharClass::single(' '))), 0, None);
+        let ast = Ast::Concat(vec![whitespace, ast]);
+        let mut grammar = Grammar {
+            states: vec![State::Match],
+            start: 0,
+        };
+        grammar.start = grammar.compile(&ast, 0)?;
+        Ok(grammar)
+    }
+
+    // Add the states for `ast` to the automaton, returning where they start.
+    // `next` is where the automaton should go once `ast` has matched.
+    fn compile(&mut self, ast: &Ast, next: usize) -> Result<usize> {
+        match ast {
+            Ast::Empty => Ok(next),
+            Ast::Class(class) => self.push(State::Char(class.clone(), next)),
+            Ast::Concat(items) => items
+                .iter()
+                .rev()
+                .try_fold(next, |next, item| self.compile(item, next)),
+            Ast::Alternate(branches) => {
+                let starts = branches
+                    .iter()
+                    .map(|b| self.compile(b, next))
+                    .collect::<Result<_>>()?;
+                self.push(State::Split(starts))
+            }
+            Ast::Repeat(inner, min, max) => {
+                let mut next = match max {
+                    None => {
+                        let repeat = self.push(State::Split(vec![]))?;
+                        let body = self.compile(inner, repeat)?;
+                        self.states[repeat] = State::Split(vec![body, next]);
+                        repeat
+                    }
+                    Some(max) => (*min..*max).try_fold(next, |next, _| {
+                        let body = self.compile(inner, next)?;
+                        self.push(State::Split(vec![body, next]))
+                    })?,
+                };
+                for _ in 0..*min {
+                    next = self.compile(inner, next)?;
+                }
+                Ok(next)
+            }
+        }
+    }
+
+    fn push(&mut self, state: State) -> Result<usize> {
+        if self.states.len() >= MAX_STATES {
+            bail!("grammar is too complex, it needs more than {MAX_STATES} states");
+        }
+        self.states.push(state);
+        Ok(self.states.len() - 1)
+    }
+
+    pub fn start(&self) -> Position {
+        self.closure(vec![self.start])
+    }
+
+    // Follow any Split states, so that the position only has Char and Match states
+    fn closure(&self, mut pending: Vec<usize>) -> Position {
+        let mut visited = vec![false; self.states.len()];
+        let mut position = vec![];
+        while let Some(state) = pending.pop() {
+            if std::mem::replace(&mut visited[state], true) {
+                continue;
+            }
+            match &self.states[state] {
+                State::Split(next) => pending.extend(next),
+                _ => position.push(state),
+            }
+        }
+        position.sort_unstable();
+        position
+    }
+
+    // Where the automaton ends up after reading `text`, or None if the text cannot match
+    pub fn advance(&self, position: &Position, text: &str) -> Option<Position> {
+        let mut position = position.clone();
+        for c in text.chars() {
+            let next = position
+                .iter()
+                .filter_map(|&state| match &self.states[state] {
+                    State::Char(class, next) if class.matches(c) => Some(*next),
+                    _ => None,
+                })
+                .collect();
+            position = self.closure(next);
+            if position.is_empty() {
+                return None;
+            }
+        }
+        Some(position)
+    }
+
+    pub fn is_match(&self, position: &Position) -> bool {
+        position
+            .iter()
+            .any(|&state| matches!(self.states[state], State::Match))
+    }
+}
+
+// A grammar along with the text of each token in the model's vocabulary
+pub struct ConstrainedVocabulary {
+    grammar: Grammar,
+    // None for special tokens and for tokens that are only part of a character
+    token_texts: Vec<Option<String>>,
+    eot_token: u32,
+    masks: HashMap<Position, Vec<f32>>,
+}
+
+impl ConstrainedVocabulary {
+    pub fn new(
+        grammar: Grammar,
+        tokenizer: &Tokenizer,
+        vocab_size: usize,
+        eot_token: u32,
+    ) -> Result<ConstrainedVocabulary> {
+        let mut token_texts = Vec::with_capacity(vocab_size);
+        for id in 0..vocab_size as u32 {
+            // Special tokens all come after the end of text token
+            let text = if id >= eot_token {
+                None
+            } else {
+                let text = tokenizer.decode(&[id], false).map_err(anyhow::Error::msg)?;
+                Some(text).filter(|t| !t.is_empty() && !t.contains(char::REPLACEMENT_CHARACTER))
+            };
+            token_texts.push(text);
+        }
+        Ok(ConstrainedVocabulary::with_token_texts(
+            grammar,
+            token_texts,
+            eot_token,
+        ))
+    }
+
+    fn with_token_texts(
+        grammar: Grammar,
+        token_texts: Vec<Option<String>>,
+        eot_token: u32,
+    ) -> ConstrainedVocabulary {
+        ConstrainedVocabulary {
+            grammar,
+            token_texts,
+            eot_token,
+            masks: HashMap::new(),
+        }
+    }
+
+    // Where the grammar starts, before the first token
+    pub fn start(&self) -> Position {
+        self.grammar.start()
+    }
+
+    // Where the grammar goes after `token`.  The mask only allows tokens that
+    // lead somewhere, so a dead end (an empty position) only allows the end.
+    pub fn advance(&self, position: &Position, token: u32) -> Position {
+        self.token_texts
+            .get(token as usize)
+            .and_then(Option::as_ref)
+            .and_then(|text| self.grammar.advance(position, text))
+            .unwrap_or_default()
+    }
+
+    // A mask to add to the logits: 0 for the tokens that the grammar allows
+    // at `position`, and negative infinity for the rest.
+    pub fn mask(&mut self, position: &Position) -> &[f32] {
+        if !self.masks.contains_key(position) {
+            if self.masks.len() >= MAX_CACHED_MASKS {
+                self.masks.clear();
+            }
+            let mask = self.build_mask(position);
+            self.masks.insert(position.clone(), mask);
+        }
+        &self.masks[position]
+    }
+
+    fn build_mask(&self, position: &Position) -> Vec<f32> {
+        let mut mask = vec![f32::NEG_INFINITY; self.token_texts.len()];
+        let mut any_allowed = false;
+        for (id, token_text) in self.token_texts.iter().enumerate() {
+            if let Some(token_text) = token_text
+                && self.grammar.advance(position, token_text).is_some()
+            {
+                mask[id] = 0.0;
+                any_allowed = true;
+            }
+        }
+        if self.grammar.is_match(position) || !any_allowed {
+            mask[self.eot_token as usize] = 0.0;
+        }
+        mask
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, text: &str) -> bool {
+        let grammar = Grammar::new(pattern).unwrap();
+        grammar
+            .advance(&grammar.start(), text)
+            .is_some_and(|position| grammar.is_match(&position))
+    }
+
+    fn could_match(pattern: &str, text: &str) -> bool {
+        let grammar = Grammar::new(pattern).unwrap();
+        grammar.advance(&grammar.start(), text).is_some()
+    }
+
+    #[test]
+    fn it_matches_isbns() {
+        let isbn = r"97[89]-?\d{1,5}-?\d+-?\d+-?[\dX]";
+        assert!(matches(isbn, " 978-0-596-52068-7"));
+        assert!(matches(isbn, "9780596520687"));
+        assert!(!matches(isbn, "978-0"));
+        assert!(could_match(isbn, "978-0"));
+        assert!(!could_match(isbn, "the complete book of cheese"));
+    }
+
+    #[test]
+    fn it_matches_commands() {
+        let commands = "(next|previous) page|start over";
+        assert!(matches(commands, " Next page"));
+        assert!(matches(commands, "start over"));
+        assert!(!matches(commands, "next"));
+        assert!(could_match(commands, "prev"));
+        assert!(!could_match(commands, "next chapter"));
+    }
+
+    #[test]
+    fn it_matches_call_numbers() {
+        let call_number = r"[A-Z]{1,3} ?\d{1,4}(\.\d+)?( ?\.?[A-Z]\d+)*";
+        assert!(matches(call_number, "PS 3545.I345 Z5"));
+        assert!(matches(call_number, "qa76 .A1"));
+        assert!(!matches(call_number, "3545"));
+    }
+
+    #[test]
+    fn it_supports_sets_and_repetition() {
+        assert!(matches("[^a]+", "bcd"));
+        assert!(!matches("[^a]+", "bad"));
+        assert!(matches("a{2,}", "aaaa"));
+        assert!(!matches("a{2,}", "a"));
+        assert!(matches("x[a-]y", "x-y"));
+        assert!(matches("a.c", "abc"));
+        assert!(matches(r"a\.c", "a.c"));
+        assert!(!matches(r"a\.c", "abc"));
+    }
+
+    #[test]
+    fn it_rejects_invalid_patterns() {
+        assert!(Grammar::new("(abc").is_err());
+        assert!(Grammar::new("abc)").is_err());
+        assert!(Grammar::new("[abc").is_err());
+        assert!(Grammar::new("*abc").is_err());
+        assert!(Grammar::new("a{3,1}").is_err());
+        assert!(Grammar::new("a{1000000}").is_err());
+        // Each repeat is small, but together they would need a million states
+        assert!(Grammar::new("((a{100}){100}){100}").is_err());
+        assert!(Grammar::new("(a{10}){20}").is_ok());
+        assert!(Grammar::new("(a{100}){5}").is_err());
+        assert!(Grammar::new("[z-a]").is_err());
+    }
+
+    #[test]
+    fn it_masks_tokens_that_would_break_the_grammar() {
+        let mut vocabulary = ConstrainedVocabulary::with_token_texts(
+            Grammar::new("next page|new search").unwrap(),
+            vec![
+                Some(" next".to_owned()),
+                Some(" page".to_owned()),
+                Some(" new".to_owned()),
+                Some(" cheese".to_owned()),
+                None,
+            ],
+            4,
+        );
+        let start = vocabulary.start();
+        assert_eq!(
+            vocabulary.mask(&start),
+            vec![
+                0.0,
+                f32::NEG_INFINITY,
+                0.0,
+                f32::NEG_INFINITY,
+                f32::NEG_INFINITY
+            ]
+        );
+        let next = vocabulary.advance(&start, 0);
+        assert_eq!(
+            vocabulary.mask(&next),
+            vec![
+                f32::NEG_INFINITY,
+                0.0,
+                f32::NEG_INFINITY,
+                f32::NEG_INFINITY,
+                f32::NEG_INFINITY
+            ]
+        );
+        let next_page = vocabulary.advance(&next, 1);
+        assert_eq!(
+            vocabulary.mask(&next_page),
+            vec![
+                f32::NEG_INFINITY,
+                f32::NEG_INFINITY,
+                f32::NEG_INFINITY,
+                f32::NEG_INFINITY,
+                0.0
+            ]
+        );
+        // A token that the mask didn't allow leads to a dead end, where only the end is allowed
+        let dead_end = vocabulary.advance(&start, 3);
+        assert!(dead_end.is_empty());
+        assert_eq!(vocabulary.mask(&dead_end)[..4], [f32::NEG_INFINITY; 4]);
+        assert_eq!(vocabulary.mask(&dead_end)[4], 0.0);
+    }
+}
@@ -0,0 +1,213 @@
+// This module is responsible for nudging the model toward phrases
+// from our catalog (author names, series, places) that it would
+// otherwise misrecognize.
+//
+// The phrases live in a plain text file, one per line, and are re-read
+// whenever the file changes, so the list can be updated without a restart.
+//
+// During decoding, we look for places where the end of the partial
+// transcription matches the beginning of a phrase, and add a bonus to the
+// logits of the tokens that would continue the phrase.  This is sometimes
+// called "shallow fusion" or "contextual biasing", see https://arxiv.org/abs/2104.02194
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::Path,
+    sync::{Arc, RwLock},
+    time::SystemTime,
+};
+
+use anyhow::Result;
+use tokenizers::Tokenizer;
+
+use crate::config;
+
+static PHRASES: RwLock<PhraseFile> = RwLock::new(PhraseFile {
+    phrases: None,
+    modified: None,
+});
+
+// The current list of phrases from config::HOTWORDS_FILE, reloaded if the file has changed
+pub fn phrases() -> Arc<Vec<String>> {
+    let path = Path::new(config::HOTWORDS_FILE);
+    if let Some(phrases) = PHRASES.read().unwrap().current(path) {
+        return phrases;
+    }
+    PHRASES.write().unwrap().reload(path)
+}
+
+struct PhraseFile {
+    phrases: Option<Arc<Vec<String>>>,
+    modified: Option<SystemTime>,
+}
+
+impl PhraseFile {
+    fn current(&self, path: &Path) -> Option<Arc<Vec<String>>> {
+        if self.modified == modified(path) {
+            self.phrases.clone()
+        } else {
+            None
+        }
+    }
+
+    fn reload(&mut self, path: &Path) -> Arc<Vec<String>> {
+        if let Some(phrases) = self.current(path) {
+            return phrases;
+        }
+        let phrases = Arc::new(match fs::read_to_string(path) {
+            Ok(contents) => parse_phrases(&contents),
+            Err(_) => vec![],
+        });
+        log::info!("Loaded {} hotwords from {:?}", phrases.len(), path);
+        self.phrases = Some(phrases.clone());
+        self.modified = modified(path);
+        phrases
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+// One phrase per line.  Blank lines and lines starting with # are ignored.
+fn parse_phrases(contents: &str) -> Vec<String> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_owned)
+        .collect()
+}
+
+#[derive(Debug, Default)]
+struct Node {
+    children: HashMap<u32, usize>,
+}
+
+// A trie of token sequences
+#[derive(Debug)]
+pub struct Trie {
+    nodes: Vec<Node>,
+    depth: usize,
+}
+
+impl Trie {
+    pub fn new<I: IntoIterator<Item = Vec<u32>>>(sequences: I) -> Trie {
+        let mut trie = Trie {
+            nodes: vec![Node::default()],
+            depth: 0,
+        };
+        for sequence in sequences {
+            trie.insert(&sequence);
+        }
+        trie
+    }
+
+    // Phrases can appear at the start of a transcription or after a space,
+    // and the tokenizer encodes those differently, so we add both.
+    pub fn from_phrases(tokenizer: &Tokenizer, phrases: &[String]) -> Result<Trie> {
+        let mut sequences = vec![];
+        for phrase in phrases {
+            for text in [phrase.clone(), format!(" {phrase}")] {
+                let encoding = tokenizer.encode(text, false).map_err(anyhow::Error::msg)?;
+                sequences.push(encoding.get_ids().to_vec());
+            }
+        }
+        Ok(Trie::new(sequences))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes[0].children.is_empty()
+    }
+
+    fn insert(&mut self, sequence: &[u32]) {
+        let mut node = 0;
+        for token in sequence {
+            node = match self.nodes[node].children.get(token) {
+                Some(&child) => child,
+                None => {
+                    self.nodes.push(Node::default());
+                    let child = self.nodes.len() - 1;
+                    self.nodes[node].children.insert(*token, child);
+                    child
+                }
+            };
+        }
+        self.depth = self.depth.max(sequence.len());
+    }
+
+    // The node reached by following the tokens from the root, if any
+    fn walk(&self, tokens: &[u32]) -> Option<usize> {
+        tokens.iter().try_fold(0, |node, token| {
+            self.nodes[node].children.get(token).copied()
+        })
+    }
+
+    // Tokens that would continue a phrase that the hypothesis is partway through
+    pub fn continuations(&self, hypothesis: &[u32]) -> Vec<u32> {
+        let mut continuations = vec![];
+        let earliest = hypothesis.len().saturating_sub(self.depth);
+        for start in earliest..hypothesis.len() {
+            if let Some(node) = self.walk(&hypothesis[start..]) {
+                continuations.extend(self.nodes[node].children.keys());
+            }
+        }
+        continuations.sort_unstable();
+        continuations.dedup();
+        continuations
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_parses_one_phrase_per_line() {
+        let contents = "# Authors\nPaul Laurence Dunbar\n\n  Artur de Azevedo  \n";
+        assert_eq!(
+            parse_phrases(contents),
+            vec!["Paul Laurence Dunbar", "Artur de Azevedo"]
+        );
+    }
+
+    #[test]
+    fn it_suggests_tokens_that_continue_a_phrase() {
+        let trie = Trie::new(vec![vec![10, 11, 12], vec![10, 13]]);
+        assert_eq!(trie.continuations(&[1, 2, 10]), vec![11, 13]);
+        assert_eq!(trie.continuations(&[1, 10, 11]), vec![12]);
+    }
+
+    #[test]
+    fn it_suggests_nothing_outside_a_phrase() {
+        let trie = Trie::new(vec![vec![10, 11, 12]]);
+        assert!(trie.continuations(&[]).is_empty());
+        assert!(trie.continuations(&[1, 2, 3]).is_empty());
+        assert!(trie.continuations(&[10, 11, 12]).is_empty());
+        assert!(trie.continuations(&[11]).is_empty());
+    }
+
+    #[test]
+    fn it_reloads_phrases_when_the_file_changes() {
+        let path = std::env::temp_dir().join("voice_search_server_hotwords_test.txt");
+        fs::write(&path, "Paul Laurence Dunbar\n").unwrap();
+        let mut file = PhraseFile {
+            phrases: None,
+            modified: None,
+        };
+        assert_eq!(*file.reload(&path), vec!["Paul Laurence Dunbar"]);
+
+        fs::write(&path, "Artur de Azevedo\n").unwrap();
+        let later = SystemTime::now() + std::time::Duration::from_secs(5);
+        fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+        assert!(file.current(&path).is_none());
+        assert_eq!(*file.reload(&path), vec!["Artur de Azevedo"]);
+        fs::remove_file(&path).unwrap();
+    }
+}
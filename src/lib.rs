@@ -0,0 +1,47 @@
+//! Transcribes voice searches with [whisper](https://github.com/openai/whisper).
+//!
+//! Other rust services can transcribe recordings with a [`Pipeline`]:
+//!
+//! ```no_run
+//! use voice_search_server::{Audio, DecodingOptions, Pipeline};
+//!
+//! // The same models as the server, from models.json
+//! let pipeline = Pipeline::from_deployment();
+//! let recording = std::fs::read("test_data/english/complete_book_of_cheese_mono.webm")?;
+//! let transcript = pipeline.transcribe(Audio::Recording(&recording), &DecodingOptions::default())?;
+//! println!("{} ({:?})", transcript.text, transcript.outcome);
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+//!
+//! The `voice_search_server` binary is a thin wrapper around this crate: a
+//! websocket [`server`], plus commands to transcribe files [`offline`] and to
+//! [`quantize`] models.
+
+mod audio;
+mod config;
+mod decoding_options;
+mod feature_extraction;
+mod grammar;
+mod hotwords;
+mod model_registry;
+pub mod offline;
+mod pipeline;
+pub mod quantize;
+pub mod server;
+mod session;
+mod transcriber;
+mod transcript;
+mod transcription;
+mod whisper_repo;
+
+pub use audio::{
+    AudioDecoder, ChannelSelection, DecodedAudio, DecoderRegistry, RawPcm, SampleFormat,
+    UnsupportedFormat,
+};
+pub use decoding_options::DecodingOptions;
+pub use model_registry::ModelSpec;
+pub use pipeline::{Audio, Pipeline};
+pub use transcriber::Transcriber;
+pub use transcript::{
+    Outcome, ResponseFormat, Segment, TokenConfidence, Transcript, WordConfidence,
+};
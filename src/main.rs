@@ -1,90 +1,101 @@
-use actix_web::{App, Error, HttpRequest, HttpResponse, HttpServer, middleware::Logger, rt, web};
-use actix_ws::AggregatedMessage;
+use clap::{Parser, Subcommand};
 use env_logger::Env;
-use futures::channel::mpsc::channel;
-use futures_util::StreamExt as _;
-use whisper_repo::WhisperRepo;
-use std::io::Cursor;
-mod audio;
-mod config;
-mod feature_extraction;
-mod transcription;
-mod whisper_repo;
+use std::path::PathBuf;
+use voice_search_server::{
+    ChannelSelection, DecodingOptions, Pipeline, ResponseFormat, offline, quantize, server,
+};
 
-async fn websocket_server(req: HttpRequest, stream: web::Payload) -> Result<HttpResponse, Error> {
-    let (res, mut session, stream) = actix_ws::handle(&req, stream)?;
+#[derive(Parser)]
+#[command(about = "A websocket server that transcribes voice searches")]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+}
 
-    let mut stream = stream
-        .max_frame_size(1024 * 1024)
-        .aggregate_continuations()
-        .max_continuation_size(2_usize.pow(20));
+#[derive(Subcommand)]
+enum Command {
+    /// Start the websocket server (the default)
+    Serve,
+    /// Convert a safetensors whisper checkpoint into a quantized GGUF file
+    Quantize {
+        /// The safetensors file with the model's weights
+        checkpoint: PathBuf,
+        /// The model's config.json
+        config: PathBuf,
+        /// Where to write the GGUF file
+        output: PathBuf,
+        #[arg(long, value_enum, default_value = "q8_0")]
+        quantization: quantize::Quantization,
+    },
+    /// Transcribe recordings (webm, Ogg, MP4, WAV, FLAC or MP3) and print the transcripts
+    Transcribe {
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+        #[arg(long, value_enum, default_value = "text")]
+        format: ResponseFormat,
+        /// The name of the model to use, from models.json
+        #[arg(long)]
+        model: Option<String>,
+        /// The language spoken in the recordings, e.g. en
+        #[arg(long)]
+        language: Option<String>,
+        /// How to turn stereo recordings into mono: downmix, loudest, or a channel number
+        #[arg(long)]
+        channel: Option<ChannelSelection>,
+        /// Text to prime the model with
+        #[arg(long)]
+        prompt: Option<String>,
+        /// A pattern that the transcriptions must match
+        #[arg(long)]
+        grammar: Option<String>,
+    },
+}
 
-    rt::spawn(async move {
-        while let Some(msg) = stream.next().await {
-            match msg {
-                Ok(AggregatedMessage::Binary(bin)) => {
-                    log::info!("Received binary websocket message");
-                    let (samples, _) = audio::pcm_decode(Cursor::new(bin)).unwrap();
-                    let features = feature_extraction::extract_features(samples).unwrap();
-                    let (mut sender, mut receiver) = channel(5);
-                    let _ = transcription::transcribe(features, &mut sender);
-                    let transcription = receiver.try_next().unwrap().unwrap();
-                    log::info!("Transcription complete: {}", transcription);
-                    session.text(transcription).await.unwrap();
-                }
-                Err(err) => {
-                    log::error!("Received a websocket message that caused error: {:?}", err)
-                }
-                _ => {}
+fn main() -> anyhow::Result<()> {
+    env_logger::init_from_env(Env::default().default_filter_or("info"));
+    match Cli::parse().command.unwrap_or(Command::Serve) {
+        Command::Serve => serve()?,
+        Command::Quantize {
+            checkpoint,
+            config,
+            output,
+            quantization,
+        } => quantize::quantize(&checkpoint, &config, &output, quantization)?,
+        Command::Transcribe {
+            files,
+            format,
+            model,
+            language,
+            channel,
+            prompt,
+            grammar,
+        } => {
+            let defaults = DecodingOptions::deployment_defaults()?.clone();
+            let options = DecodingOptions {
+                model: model.or(defaults.model),
+                language: language.or(defaults.language),
+                channel: channel.unwrap_or(defaults.channel),
+                prompt: prompt.or(defaults.prompt),
+                grammar: grammar.or(defaults.grammar),
+                ..defaults
+            };
+            options.validate()?;
+            let failures = offline::transcribe_files(
+                &Pipeline::from_deployment(),
+                &files,
+                &options,
+                format,
+                &mut std::io::stdout().lock(),
+            )?;
+            if failures > 0 {
+                anyhow::bail!("Could not transcribe {failures} of {} files", files.len());
             }
         }
-    });
-    Ok(res)
+    }
+    Ok(())
 }
 
 #[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    WhisperRepo::get();
-    env_logger::init_from_env(Env::default().default_filter_or("info"));
-    HttpServer::new(|| {
-        App::new()
-            .route("/", web::get().to(websocket_server))
-            .wrap(Logger::default())
-    })
-    .bind(("127.0.0.1", 7025))?
-    .run()
-    .await
-}
-
-#[cfg(test)]
-mod tests {
-    use std::fs;
-
-    use super::*;
-    use actix_http::ws;
-
-    use actix_web::{App, web::Bytes};
-    use futures_util::SinkExt as _;
-
-    #[actix_web::test]
-    async fn test_websocket_transcribes_binary_message() {
-        let mut server =
-            actix_test::start(|| App::new().route("/", web::get().to(websocket_server)));
-        let mut socket = server.ws().await.unwrap();
-        socket
-            .send(ws::Message::Binary(
-                fs::read("./test_data/english/complete_book_of_cheese_mono.webm")
-                    .unwrap()
-                    .into(),
-            ))
-            .await
-            .unwrap();
-        let item = socket.next().await.unwrap().unwrap();
-        assert_eq!(
-            item,
-            ws::Frame::Text(Bytes::from_static(
-                b" The Complete Book of Cheese by Robert Carlton Brown"
-            ))
-        );
-    }
+async fn serve() -> std::io::Result<()> {
+    server::serve().await
 }
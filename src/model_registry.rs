@@ -0,0 +1,452 @@
+// This module keeps track of the whisper models that this deployment hosts,
+// and decides which one should transcribe each recording.
+//
+// The models are listed in config::MODELS_FILE, e.g.
+//
+//   {"models": [
+//     {"name": "english", "repo_id": "openai/whisper-base.en", "model_filename": "model.safetensors",
+//      "languages": ["en"], "max_duration_secs": 10},
+//     {"name": "turbo"}
+//   ]}
+//
+// Any field other than the name can be left out, and defaults to the settings in config.rs.
+// A model can also be read from a local directory with "path" instead of downloaded.
+//
+// A client can ask for a model by name (see the session module).  Otherwise, we use the first
+// model whose routing rules all match the recording: "languages" (the client's language hint
+// must be one of them) and "max_duration_secs".  A model without rules matches any recording.
+// If nothing matches, we use the first model.
+//
+// If the file does not exist, we host a single model named "default".
+//
+// To change models without restarting, edit the file and send the server a SIGHUP or
+// POST /admin/reload (on config::ADMIN_PORT).  We download the new models' files in the background and check
+// each of them with a test inference before swapping them in.  Only the files are
+// swapped: each request still loads the weights from them.  New requests use the new
+// models, while requests that are already in progress finish with the old ones.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, OnceLock, RwLock},
+};
+
+use anyhow::{Context, Result, anyhow, bail};
+use serde::Deserialize;
+
+use crate::{
+    config, decoding_options::DecodingOptions, feature_extraction::extract_features, transcription,
+    whisper_repo::WhisperRepo,
+};
+
+static MODEL_REGISTRY: OnceLock<Reloadable> = OnceLock::new();
+
+/// Where to find a whisper model, and when to use it.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ModelSpec {
+    pub name: String,
+    /// The HuggingFace repo with the model's files
+    #[serde(default = "default_repo_id")]
+    pub repo_id: String,
+    #[serde(default = "default_revision")]
+    pub revision: String,
+    /// A local directory containing the model's files, instead of the HuggingFace repo
+    pub path: Option<PathBuf>,
+    /// A gguf or safetensors file with the model's weights
+    #[serde(default = "default_model_filename")]
+    pub model_filename: String,
+    #[serde(default = "default_tokenizer_filename")]
+    pub tokenizer_filename: String,
+    #[serde(default = "default_config_filename")]
+    pub config_filename: String,
+    /// Only use this model when the language hint is one of these, unless it is asked for by name
+    #[serde(default)]
+    pub languages: Vec<String>,
+    /// Only use this model for recordings up to this long, unless it is asked for by name
+    pub max_duration_secs: Option<f64>,
+}
+
+fn default_repo_id() -> String {
+    config::REPO_ID.to_owned()
+}
+
+fn default_revision() -> String {
+    "main".to_owned()
+}
+
+fn default_model_filename() -> String {
+    config::MODEL_FILENAME.to_owned()
+}
+
+fn default_tokenizer_filename() -> String {
+    config::TOKENIZER_FILENAME.to_owned()
+}
+
+fn default_config_filename() -> String {
+    config::MODEL_CONFIG_FILENAME.to_owned()
+}
+
+impl ModelSpec {
+    /// The quantized whisper large v3 turbo model that the server uses by default
+    pub fn new(name: &str) -> ModelSpec {
+        ModelSpec {
+            name: name.to_owned(),
+            repo_id: default_repo_id(),
+            revision: default_revision(),
+            path: None,
+            model_filename: default_model_filename(),
+            tokenizer_filename: default_tokenizer_filename(),
+            config_filename: default_config_filename(),
+            languages: vec![],
+            max_duration_secs: None,
+        }
+    }
+
+    fn matches(&self, language: Option<&str>, duration_secs: f64) -> bool {
+        let language_matches = self.languages.is_empty()
+            || language.is_some_and(|language| {
+                self.languages
+                    .iter()
+                    .any(|l| l.eq_ignore_ascii_case(language))
+            });
+        let duration_matches = self
+            .max_duration_secs
+            .is_none_or(|max_duration_secs| duration_secs <= max_duration_secs);
+        language_matches && duration_matches
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ModelsFile {
+    models: Vec<ModelSpec>,
+}
+
+#[derive(Debug)]
+pub struct Model {
+    pub spec: ModelSpec,
+    repo: OnceLock<WhisperRepo>,
+}
+
+impl Model {
+    pub fn name(&self) -> &str {
+        &self.spec.name
+    }
+
+    // The model's files, which are downloaded the first time they are needed
+    pub fn try_repo(&self) -> Result<&WhisperRepo> {
+        if let Some(repo) = self.repo.get() {
+            return Ok(repo);
+        }
+        let repo = WhisperRepo::download(&self.spec)?;
+        Ok(self.repo.get_or_init(|| repo))
+    }
+}
+
+#[derive(Debug)]
+pub struct ModelRegistry {
+    models: Vec<Model>,
+}
+
+impl ModelRegistry {
+    // The models for this deployment, read from config::MODELS_FILE if it exists.
+    // Errors if the file is malformed.  Hold on to the result for the whole
+    // request, so that a reload doesn't change the models partway through.
+    pub fn get() -> Result<Arc<ModelRegistry>> {
+        Ok(deployment()?.current())
+    }
+
+    // Re-read config::MODELS_FILE, and swap in the new models once they are ready.
+    // This takes a while, so call it from a background thread.
+    pub fn reload() -> Result<Arc<ModelRegistry>> {
+        deployment()?.reload(Path::new(config::MODELS_FILE), test_inference)
+    }
+
+    pub fn new(specs: Vec<ModelSpec>) -> Result<ModelRegistry> {
+        if specs.is_empty() {
+            bail!("There must be at least one model");
+        }
+        for (i, spec) in specs.iter().enumerate() {
+            if specs[..i].iter().any(|other| other.name == spec.name) {
+                bail!("There is more than one model named {:?}", spec.name);
+            }
+        }
+        Ok(ModelRegistry {
+            models: specs
+                .into_iter()
+                .map(|spec| Model {
+                    spec,
+                    repo: OnceLock::new(),
+                })
+                .collect(),
+        })
+    }
+
+    // Download every model, so that the first requests don't have to wait
+    pub fn download_all(&self) -> Result<()> {
+        for model in &self.models {
+            log::info!("Loading model {}", model.name());
+            model
+                .try_repo()
+                .with_context(|| format!("Could not load model {}", model.name()))?;
+        }
+        Ok(())
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.models.iter().map(Model::name).collect()
+    }
+
+    pub fn default_model(&self) -> &Model {
+        &self.models[0]
+    }
+
+    pub fn named(&self, name: &str) -> Result<&Model> {
+        self.models
+            .iter()
+            .find(|model| model.name() == name)
+            .ok_or_else(|| {
+                anyhow!(
+                    "There is no model named {name:?}, choose one of {}",
+                    self.names().join(", ")
+                )
+            })
+    }
+
+    // The model that should transcribe a recording
+    pub fn route(
+        &self,
+        name: Option<&str>,
+        language: Option<&str>,
+        duration_secs: f64,
+    ) -> Result<&Model> {
+        if let Some(name) = name {
+            return self.named(name);
+        }
+        Ok(self
+            .models
+            .iter()
+            .find(|model| model.spec.matches(language, duration_secs))
+            .unwrap_or(self.default_model()))
+    }
+}
+
+// The deployment's models, which are read the first time they are needed
+fn deployment() -> Result<&'static Reloadable> {
+    if let Some(reloadable) = MODEL_REGISTRY.get() {
+        return Ok(reloadable);
+    }
+    let registry = load(Path::new(config::MODELS_FILE))?;
+    Ok(MODEL_REGISTRY.get_or_init(|| Reloadable::new(registry)))
+}
+
+// The current ModelRegistry, which can be swapped for a new one at any time
+struct Reloadable {
+    current: RwLock<Arc<ModelRegistry>>,
+    reloading: Mutex<()>,
+}
+
+impl Reloadable {
+    fn new(registry: ModelRegistry) -> Reloadable {
+        Reloadable {
+            current: RwLock::new(Arc::new(registry)),
+            reloading: Mutex::new(()),
+        }
+    }
+
+    fn current(&self) -> Arc<ModelRegistry> {
+        self.current.read().unwrap().clone()
+    }
+
+    // If anything goes wrong, we keep the current models
+    fn reload(
+        &self,
+        path: &Path,
+        check: impl Fn(&Model) -> Result<()>,
+    ) -> Result<Arc<ModelRegistry>> {
+        let _reloading = self
+            .reloading
+            .try_lock()
+            .map_err(|_| anyhow!("The models are already being reloaded"))?;
+        let registry = load(path)?;
+        for model in &registry.models {
+            log::info!("Loading model {}", model.name());
+            model
+                .try_repo()
+                .and_then(|_| check(model))
+                .with_context(|| format!("Could not load model {}", model.name()))?;
+        }
+        let registry = Arc::new(registry);
+        *self.current.write().unwrap() = registry.clone();
+        log::info!("Reloaded models: {}", registry.names().join(", "));
+        Ok(registry)
+    }
+}
+
+// Make sure that the model can transcribe a second of silence
+fn test_inference(model: &Model) -> Result<()> {
+    let silence = vec![0.0; config::AUDIO_DECODE_SAMPLE_RATE as usize];
+    let repo = model.try_repo()?;
+    let features = extract_features(silence, repo)?;
+    transcription::transcribe(features, repo, &DecodingOptions::default())?;
+    Ok(())
+}
+
+fn load(path: &Path) -> Result<ModelRegistry> {
+    if !path.exists() {
+        return ModelRegistry::new(vec![ModelSpec::new("default")]);
+    }
+    let file: ModelsFile = serde_json::from_str(&fs::read_to_string(path)?)
+        .with_context(|| format!("Could not read models from {path:?}"))?;
+    ModelRegistry::new(file.models).with_context(|| format!("Invalid models in {path:?}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registry() -> ModelRegistry {
+        let file: ModelsFile = serde_json::from_str(
+            r#"{"models": [
+                {"name": "english", "repo_id": "openai/whisper-base.en", "languages": ["en"], "max_duration_secs": 10},
+                {"name": "turbo"}
+            ]}"#,
+        )
+        .unwrap();
+        ModelRegistry::new(file.models).unwrap()
+    }
+
+    #[test]
+    fn it_fills_in_missing_fields_from_config() {
+        let registry = registry();
+        let turbo = &registry.named("turbo").unwrap().spec;
+        assert_eq!(turbo.repo_id, config::REPO_ID);
+        assert_eq!(turbo.model_filename, config::MODEL_FILENAME);
+        assert_eq!(
+            registry.named("english").unwrap().spec.repo_id,
+            "openai/whisper-base.en"
+        );
+    }
+
+    #[test]
+    fn it_routes_by_name() {
+        let registry = registry();
+        assert_eq!(
+            registry
+                .route(Some("turbo"), Some("en"), 1.0)
+                .unwrap()
+                .name(),
+            "turbo"
+        );
+        let err = registry.route(Some("tiny"), None, 1.0).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            r#"There is no model named "tiny", choose one of english, turbo"#
+        );
+    }
+
+    #[test]
+    fn it_routes_by_language_and_duration() {
+        let registry = registry();
+        assert_eq!(
+            registry.route(None, Some("EN"), 5.0).unwrap().name(),
+            "english"
+        );
+        assert_eq!(
+            registry.route(None, Some("en"), 20.0).unwrap().name(),
+            "turbo"
+        );
+        assert_eq!(
+            registry.route(None, Some("pt"), 5.0).unwrap().name(),
+            "turbo"
+        );
+        assert_eq!(registry.route(None, None, 5.0).unwrap().name(), "turbo");
+    }
+
+    #[test]
+    fn it_uses_the_first_model_when_nothing_matches() {
+        let file: ModelsFile = serde_json::from_str(
+            r#"{"models": [{"name": "turbo", "languages": ["pt"]}, {"name": "english", "languages": ["en"]}]}"#,
+        )
+        .unwrap();
+        let registry = ModelRegistry::new(file.models).unwrap();
+        assert_eq!(
+            registry.route(None, Some("ru"), 5.0).unwrap().name(),
+            "turbo"
+        );
+    }
+
+    #[test]
+    fn it_requires_unique_names() {
+        let file: ModelsFile =
+            serde_json::from_str(r#"{"models": [{"name": "turbo"}, {"name": "turbo"}]}"#).unwrap();
+        assert!(ModelRegistry::new(file.models).is_err());
+        assert!(ModelRegistry::new(vec![]).is_err());
+    }
+
+    #[test]
+    fn it_hosts_a_single_default_model_when_there_is_no_file() {
+        let registry = load(Path::new("./test_data/no_such_file.json")).unwrap();
+        assert_eq!(registry.default_model().name(), "default");
+        assert_eq!(
+            registry.route(None, Some("en"), 5.0).unwrap().name(),
+            "default"
+        );
+    }
+
+    fn write_models_file(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(name);
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn it_errors_on_a_malformed_file() {
+        let path = write_models_file("model_registry_test_malformed.json", r#"{"models": ["#);
+        assert!(load(&path).is_err());
+    }
+
+    #[test]
+    fn it_swaps_in_reloaded_models_for_new_requests_only() {
+        let reloadable = Reloadable::new(registry());
+        let in_flight = reloadable.current();
+        let path = write_models_file(
+            "model_registry_test_reload.json",
+            r#"{"models": [{"name": "small", "path": "/models/small"}]}"#,
+        );
+        reloadable.reload(&path, |_| Ok(())).unwrap();
+        assert_eq!(reloadable.current().names(), vec!["small"]);
+        assert_eq!(in_flight.names(), vec!["english", "turbo"]);
+    }
+
+    #[test]
+    fn it_keeps_the_current_models_when_a_reload_fails() {
+        let reloadable = Reloadable::new(registry());
+        let path = write_models_file(
+            "model_registry_test_failed_reload.json",
+            r#"{"models": [{"name": "small", "path": "/models/small"}]}"#,
+        );
+        let err = reloadable
+            .reload(&path, |_| Err(anyhow!("test inference failed")))
+            .unwrap_err();
+        assert_eq!(
+            format!("{err:#}"),
+            "Could not load model small: test inference failed"
+        );
+        let invalid = write_models_file("model_registry_test_invalid.json", r#"{"models": []}"#);
+        assert!(reloadable.reload(&invalid, |_| Ok(())).is_err());
+        assert_eq!(reloadable.current().names(), vec!["english", "turbo"]);
+    }
+
+    #[test]
+    fn it_reloads_one_at_a_time() {
+        let reloadable = Reloadable::new(registry());
+        let _reloading = reloadable.reloading.lock().unwrap();
+        let path = write_models_file(
+            "model_registry_test_concurrent_reload.json",
+            r#"{"models": [{"name": "small", "path": "/models/small"}]}"#,
+        );
+        assert!(reloadable.reload(&path, |_| Ok(())).is_err());
+    }
+}
@@ -0,0 +1,132 @@
+// This module is responsible for transcribing recordings on disk from the
+// command line, so that staff can check a batch of recordings or debug a
+// misrecognition without a websocket client:
+//
+//   cargo run --release -- transcribe test_data/english/*.webm --format srt
+//
+// When there is more than one file, each transcript is preceded by a
+// "==> filename <==" header.  With --format json, each transcript is
+// instead printed on its own line, with a "file" field.
+
+use std::{fs, io::Write, path::PathBuf};
+
+use anyhow::{Context, Result};
+
+use crate::{
+    decoding_options::DecodingOptions,
+    pipeline::{Audio, Pipeline},
+    transcript::ResponseFormat,
+};
+
+/// Transcribe each file and print its transcript to `out`.  Returns how many
+/// of the files could not be transcribed.
+pub fn transcribe_files(
+    pipeline: &Pipeline,
+    files: &[PathBuf],
+    options: &DecodingOptions,
+    format: ResponseFormat,
+    out: &mut impl Write,
+) -> Result<usize> {
+    let mut failures = 0;
+    for (i, path) in files.iter().enumerate() {
+        let transcript = fs::read(path)
+            .map_err(anyhow::Error::from)
+            .and_then(|recording| pipeline.transcribe(Audio::Recording(&recording), options))
+            .with_context(|| format!("Could not transcribe {path:?}"));
+        let transcript = match transcript {
+            Ok(transcript) => transcript,
+            Err(err) => {
+                log::error!("{err:#}");
+                failures += 1;
+                continue;
+            }
+        };
+        match format {
+            ResponseFormat::Json => {
+                let mut json = serde_json::to_value(&transcript)?;
+                json["file"] = path.display().to_string().into();
+                writeln!(out, "{json}")?;
+            }
+            _ => {
+                if files.len() > 1 {
+                    let separator = if i == 0 { "" } else { "\n" };
+                    writeln!(out, "{separator}==> {} <==", path.display())?;
+                }
+                let rendered = format.render(&transcript);
+                write!(out, "{}", rendered.trim_start())?;
+                if !rendered.ends_with('\n') {
+                    writeln!(out)?;
+                }
+            }
+        }
+    }
+    Ok(failures)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::transcriber::mock::MockTranscriber;
+
+    const RECORDING: &str = "./test_data/english/complete_book_of_cheese_mono.webm";
+
+    fn transcribe(files: &[&str], format: ResponseFormat) -> (String, usize) {
+        let pipeline =
+            Pipeline::with_transcriber(Arc::new(MockTranscriber::with_texts(&[" One", " Two"])));
+        let files: Vec<PathBuf> = files.iter().map(PathBuf::from).collect();
+        let mut out = vec![];
+        let failures = transcribe_files(
+            &pipeline,
+            &files,
+            &DecodingOptions::default(),
+            format,
+            &mut out,
+        )
+        .unwrap();
+        (String::from_utf8(out).unwrap(), failures)
+    }
+
+    #[test]
+    fn it_prints_the_text_of_a_single_file() {
+        assert_eq!(
+            transcribe(&[RECORDING], ResponseFormat::Text),
+            ("One\n".to_owned(), 0)
+        );
+    }
+
+    #[test]
+    fn it_prints_a_header_for_each_of_several_files() {
+        let (out, failures) = transcribe(&[RECORDING, RECORDING], ResponseFormat::Text);
+        assert_eq!(
+            out,
+            format!("==> {RECORDING} <==\nOne\n\n==> {RECORDING} <==\nTwo\n")
+        );
+        assert_eq!(failures, 0);
+    }
+
+    #[test]
+    fn it_prints_a_line_of_json_for_each_file() {
+        let (out, _) = transcribe(&[RECORDING, RECORDING], ResponseFormat::Json);
+        let lines: Vec<serde_json::Value> = out
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["file"], RECORDING);
+        assert_eq!(lines[1]["text"], " Two");
+    }
+
+    #[test]
+    fn it_carries_on_after_a_file_it_cannot_transcribe() {
+        let (out, failures) = transcribe(
+            &["./test_data/no_such_file.webm", RECORDING],
+            ResponseFormat::Text,
+        );
+        assert_eq!(failures, 1);
+        assert!(
+            out.ends_with("==> ./test_data/english/complete_book_of_cheese_mono.webm <==\nOne\n")
+        );
+    }
+}
@@ -0,0 +1,219 @@
+// This module is responsible for the API that other rust services use
+// to transcribe recordings: a Pipeline that decodes audio, checks it for
+// noise, and transcribes it with a whisper model.  The websocket server
+// and the transcribe command are built on it as well.
+
+use std::sync::Arc;
+
+use anyhow::Result;
+
+use crate::{
+    audio::{self, DecoderRegistry, RawPcm},
+    config,
+    decoding_options::DecodingOptions,
+    model_registry::{ModelRegistry, ModelSpec},
+    transcriber::{Transcriber, WhisperTranscriber},
+    transcript::Transcript,
+};
+
+/// Audio for a [`Pipeline`] to transcribe.
+#[derive(Debug, Clone, Copy)]
+pub enum Audio<'a> {
+    /// A webm recording with opus or vorbis audio, an Ogg recording with opus
+    /// audio, or an MP4 recording with AAC audio, like the ones that browsers
+    /// make with the MediaRecorder API.  WAV, FLAC and MP3 files work too.
+    /// Stereo recordings are turned into mono according to
+    /// [`DecodingOptions::channel`].
+    Recording(&'a [u8]),
+    /// A recording along with the MIME type that the client gave it, e.g.
+    /// `audio/ogg; codecs=opus`.  The MIME type only matters when the
+    /// recording's first bytes don't say what format it is.
+    Typed(&'a [u8], &'a str),
+    /// Mono PCM samples at the given sample rate (in Hz), which are resampled
+    /// to whatever rate the model needs.
+    Pcm(&'a [f32], u32),
+    /// Little-endian PCM bytes in any [`RawPcm`] format, e.g. from an AudioWorklet.
+    /// They are turned into mono according to [`DecodingOptions::channel`] and
+    /// resampled, without looking for a container.
+    RawPcm(&'a [u8], RawPcm),
+}
+
+/// Turns recordings into [`Transcript`]s.
+///
+/// A pipeline is cheap to clone, and can be shared between threads.
+///
+/// ```no_run
+/// use voice_search_server::{Audio, DecodingOptions, ModelSpec, Pipeline};
+///
+/// let pipeline = Pipeline::new(ModelSpec::new("turbo"))?;
+/// let recording = std::fs::read("test_data/english/complete_book_of_cheese_mono.webm")?;
+/// let options = DecodingOptions {
+///     prompt: Some("author, title".to_owned()),
+///     ..Default::default()
+/// };
+/// let transcript = pipeline.transcribe(Audio::Recording(&recording), &options)?;
+/// println!("{}", transcript.text);
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+#[derive(Clone)]
+pub struct Pipeline {
+    transcriber: Arc<dyn Transcriber>,
+    decoders: DecoderRegistry,
+}
+
+impl Pipeline {
+    /// A pipeline with a single model.  This downloads the model's files
+    /// from the HuggingFace hub, unless the spec has a local `path`.
+    pub fn new(spec: ModelSpec) -> Result<Pipeline> {
+        Pipeline::with_models(vec![spec])
+    }
+
+    /// A pipeline that chooses between several models, the same way the
+    /// server does with `models.json`.  The first model is the default.
+    pub fn with_models(specs: Vec<ModelSpec>) -> Result<Pipeline> {
+        let registry = ModelRegistry::new(specs)?;
+        registry.download_all()?;
+        Ok(Pipeline::with_transcriber(Arc::new(
+            WhisperTranscriber::with_models(Arc::new(registry)),
+        )))
+    }
+
+    /// A pipeline with this deployment's models from `models.json`, which
+    /// follows along when the server reloads them.
+    pub fn from_deployment() -> Pipeline {
+        Pipeline::with_transcriber(Arc::new(WhisperTranscriber::default()))
+    }
+
+    /// A pipeline with some other way of transcribing, e.g. a mock for tests.
+    pub fn with_transcriber(transcriber: Arc<dyn Transcriber>) -> Pipeline {
+        Pipeline {
+            transcriber,
+            decoders: DecoderRegistry::default(),
+        }
+    }
+
+    /// Decode recordings with these decoders, e.g. the defaults plus one
+    /// for a format that only your service uses.
+    pub fn with_decoders(self, decoders: DecoderRegistry) -> Pipeline {
+        Pipeline { decoders, ..self }
+    }
+
+    /// The decoders that this pipeline chooses from
+    pub fn decoders(&self) -> &DecoderRegistry {
+        &self.decoders
+    }
+
+    /// Transcribe a recording.  This takes a while, so async callers should
+    /// run it on a thread where blocking is allowed.
+    pub fn transcribe(&self, audio: Audio, options: &DecodingOptions) -> Result<Transcript> {
+        options.validate()?;
+        let (samples, dropped_packets) = match audio {
+            Audio::Recording(recording) => {
+                audio::pcm_decode_with(&self.decoders, recording, None, options.channel)?
+            }
+            Audio::Typed(recording, mime_type) => {
+                audio::pcm_decode_with(&self.decoders, recording, Some(mime_type), options.channel)?
+            }
+            Audio::Pcm(samples, sample_rate) => (audio::pcm_resample(samples, sample_rate)?, 0),
+            Audio::RawPcm(bytes, format) => {
+                (audio::raw_pcm_decode(bytes, &format, options.channel)?, 0)
+            }
+        };
+        if dropped_packets > 0 {
+            log::warn!("Dropped {dropped_packets} damaged packets of the recording");
+        }
+        let snr_db = audio::signal_to_noise_ratio(&samples);
+        let mut transcript =
+            self.transcriber
+                .transcribe(samples, config::AUDIO_DECODE_SAMPLE_RATE, options)?;
+        transcript.check_noise(snr_db, options.min_snr_db);
+        transcript.dropped_packets = dropped_packets;
+        log::info!(
+            "Transcription complete ({:?}): {}",
+            transcript.outcome,
+            transcript.text
+        );
+        Ok(transcript)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{transcriber::mock::MockTranscriber, transcript::Outcome};
+
+    #[test]
+    fn it_transcribes_webm_recordings() {
+        let pipeline = Pipeline::with_transcriber(Arc::new(MockTranscriber::with_texts(&[" One"])));
+        let recording =
+            std::fs::read("./test_data/english/complete_book_of_cheese_mono.webm").unwrap();
+        let transcript = pipeline
+            .transcribe(Audio::Recording(&recording), &DecodingOptions::default())
+            .unwrap();
+        assert_eq!(transcript.text, " One");
+    }
+
+    #[test]
+    fn it_transcribes_pcm_samples() {
+        let transcriber = Arc::new(MockTranscriber::new(vec![Ok(Transcript {
+            outcome: Outcome::NoSpeech,
+            ..Default::default()
+        })]));
+        let pipeline = Pipeline::with_transcriber(transcriber.clone());
+        // A second of noise
+        let noise: Vec<f32> = (0..16_000)
+            .map(|i| ((i * 7919) % 200) as f32 / 100.0 - 1.0)
+            .collect();
+        let transcript = pipeline
+            .transcribe(Audio::Pcm(&noise, 16_000), &DecodingOptions::default())
+            .unwrap();
+        assert_eq!(transcript.outcome, Outcome::TooNoisy);
+        assert_eq!(transcriber.requests.lock().unwrap().len(), 1);
+        assert_eq!(
+            *transcriber.sample_counts.lock().unwrap(),
+            vec![config::AUDIO_DECODE_SAMPLE_RATE as usize]
+        );
+        assert!(
+            pipeline
+                .transcribe(Audio::Pcm(&noise, 0), &DecodingOptions::default())
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn it_transcribes_raw_pcm_bytes() {
+        let transcriber = Arc::new(MockTranscriber::with_texts(&[" One"]));
+        let pipeline = Pipeline::with_transcriber(transcriber.clone());
+        let format = RawPcm {
+            sample_rate: 48_000,
+            channels: 2,
+            sample_format: audio::SampleFormat::F32le,
+        };
+        // A second of silence
+        let bytes = vec![0; format.bytes_per_second()];
+        let transcript = pipeline
+            .transcribe(Audio::RawPcm(&bytes, format), &DecodingOptions::default())
+            .unwrap();
+        assert_eq!(transcript.text, " One");
+        assert_eq!(
+            *transcriber.sample_counts.lock().unwrap(),
+            vec![config::AUDIO_DECODE_SAMPLE_RATE as usize]
+        );
+    }
+
+    #[test]
+    fn it_checks_the_options_before_transcribing() {
+        let transcriber = Arc::new(MockTranscriber::with_texts(&[" One"]));
+        let pipeline = Pipeline::with_transcriber(transcriber.clone());
+        let options = DecodingOptions {
+            max_tokens: Some(0),
+            ..Default::default()
+        };
+        assert!(
+            pipeline
+                .transcribe(Audio::Pcm(&[0.0; 100], 16_000), &options)
+                .is_err()
+        );
+        assert!(transcriber.requests.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,176 @@
+// This module is responsible for turning a full precision safetensors whisper
+// checkpoint (e.g. one we fine-tuned) into a quantized GGUF file that the
+// server can load, without needing any python tooling:
+//
+//   cargo run --release -- quantize model.safetensors config.json model-q5_0.gguf --quantization q5_0
+//
+// Only the weight matrices are quantized.  Biases, layer norms, and any matrix whose
+// rows don't divide evenly into the quantization's blocks are kept at full precision.
+
+use std::{fs::File, path::Path};
+
+use anyhow::{Context, Result};
+use candle_core::{
+    Device, Tensor,
+    quantized::{GgmlDType, QTensor, gguf_file},
+};
+use candle_transformers::{
+    models::whisper::{Config, quantized_model},
+    quantized_var_builder::VarBuilder,
+};
+use clap::ValueEnum;
+
+/// How to store the weight matrices of the quantized model
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum Quantization {
+    #[value(name = "q4_0")]
+    Q4_0,
+    #[value(name = "q4_1")]
+    Q4_1,
+    #[value(name = "q5_0")]
+    Q5_0,
+    #[value(name = "q5_1")]
+    Q5_1,
+    #[value(name = "q8_0")]
+    Q8_0,
+    Q2k,
+    Q3k,
+    Q4k,
+    Q5k,
+    Q6k,
+    F16,
+    F32,
+}
+
+impl Quantization {
+    fn dtype(self) -> GgmlDType {
+        match self {
+            Quantization::Q4_0 => GgmlDType::Q4_0,
+            Quantization::Q4_1 => GgmlDType::Q4_1,
+            Quantization::Q5_0 => GgmlDType::Q5_0,
+            Quantization::Q5_1 => GgmlDType::Q5_1,
+            Quantization::Q8_0 => GgmlDType::Q8_0,
+            Quantization::Q2k => GgmlDType::Q2K,
+            Quantization::Q3k => GgmlDType::Q3K,
+            Quantization::Q4k => GgmlDType::Q4K,
+            Quantization::Q5k => GgmlDType::Q5K,
+            Quantization::Q6k => GgmlDType::Q6K,
+            Quantization::F16 => GgmlDType::F16,
+            Quantization::F32 => GgmlDType::F32,
+        }
+    }
+}
+
+/// Write a GGUF file with the weights of a safetensors whisper checkpoint
+pub fn quantize(
+    checkpoint: &Path,
+    config_file: &Path,
+    output: &Path,
+    quantization: Quantization,
+) -> Result<()> {
+    let config: Config = serde_json::from_str(&std::fs::read_to_string(config_file)?)
+        .with_context(|| format!("Could not read a whisper config from {config_file:?}"))?;
+    let mut tensors: Vec<(String, Tensor)> =
+        candle_core::safetensors::load(checkpoint, &Device::Cpu)
+            .with_context(|| format!("Could not read safetensors from {checkpoint:?}"))?
+            .into_iter()
+            .collect();
+    tensors.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    let dtype = quantization.dtype();
+    let mut quantized = vec![];
+    for (name, tensor) in &tensors {
+        let tensor_dtype = if should_quantize(tensor, dtype) {
+            dtype
+        } else {
+            GgmlDType::F32
+        };
+        log::info!("Quantizing {name} {:?} as {tensor_dtype:?}", tensor.shape());
+        quantized.push((name.as_str(), QTensor::quantize(tensor, tensor_dtype)?));
+    }
+
+    let architecture = gguf_file::Value::String("whisper".to_owned());
+    let file_type = gguf_file::Value::String(format!("{quantization:?}").to_lowercase());
+    let metadata = [
+        ("general.architecture", &architecture),
+        ("general.file_type", &file_type),
+    ];
+    let tensors: Vec<(&str, &QTensor)> = quantized.iter().map(|(n, t)| (*n, t)).collect();
+    gguf_file::write(&mut File::create(output)?, &metadata, &tensors)?;
+
+    // Make sure that the server will be able to load what we wrote
+    let loaded = VarBuilder::from_gguf(output, &Device::Cpu)
+        .and_then(|vb| quantized_model::Whisper::load(&vb, config));
+    if let Err(err) = loaded {
+        let _ = std::fs::remove_file(output);
+        return Err(err)
+            .with_context(|| format!("{checkpoint:?} does not look like a whisper checkpoint"));
+    }
+    Ok(())
+}
+
+// Quantization works on blocks of each row of a matrix.  Vectors are small enough
+// that there would be little to gain anyway.
+fn should_quantize(tensor: &Tensor, dtype: GgmlDType) -> bool {
+    tensor.rank() == 2 && tensor.dims()[1].is_multiple_of(dtype.block_size())
+}
+
+#[cfg(test)]
+mod tests {
+    use candle_core::DType;
+    use candle_nn::VarMap;
+    use candle_transformers::models::whisper::model;
+
+    use super::*;
+
+    // A whisper model that is small enough to build in a test
+    const TINY_CONFIG: &str = r#"{"num_mel_bins": 80, "max_source_positions": 16, "d_model": 64,
+        "encoder_attention_heads": 2, "encoder_layers": 1, "vocab_size": 96,
+        "max_target_positions": 8, "decoder_attention_heads": 2, "decoder_layers": 1}"#;
+
+    #[test]
+    fn it_quantizes_a_checkpoint_that_the_quantized_model_can_load() {
+        let dir = std::env::temp_dir();
+        let checkpoint = dir.join("quantize_test_model.safetensors");
+        let config_file = dir.join("quantize_test_config.json");
+        let output = dir.join("quantize_test_model.gguf");
+        let varmap = VarMap::new();
+        let vb = candle_nn::VarBuilder::from_varmap(&varmap, DType::F32, &Device::Cpu);
+        model::Whisper::load(&vb, serde_json::from_str(TINY_CONFIG).unwrap()).unwrap();
+        varmap.save(&checkpoint).unwrap();
+        std::fs::write(&config_file, TINY_CONFIG).unwrap();
+
+        quantize(&checkpoint, &config_file, &output, Quantization::Q8_0).unwrap();
+
+        let mut file = File::open(&output).unwrap();
+        let content = gguf_file::Content::read(&mut file).unwrap();
+        let fc1 = &content.tensor_infos["model.encoder.layers.0.fc1.weight"];
+        assert_eq!(fc1.ggml_dtype, GgmlDType::Q8_0);
+        let bias = &content.tensor_infos["model.encoder.layers.0.fc1.bias"];
+        assert_eq!(bias.ggml_dtype, GgmlDType::F32);
+    }
+
+    #[test]
+    fn it_keeps_tensors_that_do_not_fit_in_blocks_at_full_precision() {
+        let tensor = Tensor::zeros((4, 64), DType::F32, &Device::Cpu).unwrap();
+        assert!(should_quantize(&tensor, GgmlDType::Q8_0));
+        assert!(!should_quantize(&tensor, GgmlDType::Q4K));
+        let bias = Tensor::zeros(64, DType::F32, &Device::Cpu).unwrap();
+        assert!(!should_quantize(&bias, GgmlDType::Q8_0));
+    }
+
+    #[test]
+    fn it_errors_on_checkpoints_that_are_not_whisper() {
+        let dir = std::env::temp_dir();
+        let checkpoint = dir.join("quantize_test_not_whisper.safetensors");
+        let config_file = dir.join("quantize_test_not_whisper_config.json");
+        let varmap = VarMap::new();
+        let vb = candle_nn::VarBuilder::from_varmap(&varmap, DType::F32, &Device::Cpu);
+        vb.get((4, 32), "some.other.weight").unwrap();
+        varmap.save(&checkpoint).unwrap();
+        std::fs::write(&config_file, TINY_CONFIG).unwrap();
+        let output = dir.join("quantize_test_not_whisper.gguf");
+        assert!(quantize(&checkpoint, &config_file, &output, Quantization::Q8_0).is_err());
+        assert!(!output.exists());
+    }
+}
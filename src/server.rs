@@ -0,0 +1,600 @@
+// This module is responsible for the websocket server.  Clients send a webm
+// recording as a binary message, and receive its transcription as a text message.
+// They can also send JSON text messages to choose settings, see the session module.
+//
+// Clients that chose raw PCM input instead send their frames in as many binary
+// messages as they like, and then an empty binary message to end the recording.
+//
+// Clients can say what format their recordings are in by asking for a websocket
+// subprotocol named after the MIME type, e.g. `new WebSocket(url, "audio.ogg")`
+// for audio/ogg.  We usually tell from the recording itself anyway.
+
+use actix_web::{
+    App, Error, HttpRequest, HttpResponse, HttpServer,
+    http::header::{HeaderValue, SEC_WEBSOCKET_PROTOCOL},
+    middleware::Logger,
+    rt, web,
+};
+use actix_ws::AggregatedMessage;
+use anyhow::bail;
+use futures_util::StreamExt as _;
+
+use crate::{
+    audio::{DecoderRegistry, Input, RawPcm},
+    config,
+    decoding_options::DecodingOptions,
+    model_registry::ModelRegistry,
+    pipeline::{Audio, Pipeline},
+    session::Session,
+    transcript::Transcript,
+};
+
+async fn websocket_server(
+    req: HttpRequest,
+    stream: web::Payload,
+    pipeline: web::Data<Pipeline>,
+) -> Result<HttpResponse, Error> {
+    let (mut res, mut session, stream) = actix_ws::handle(&req, stream)?;
+    let subprotocol = choose_subprotocol(&req, pipeline.decoders());
+    if let Some(protocol) = &subprotocol {
+        res.headers_mut()
+            .insert(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_str(protocol)?);
+    }
+    let subprotocol_mime_type = subprotocol.as_deref().and_then(subprotocol_mime_type);
+
+    let mut stream = stream
+        .max_frame_size(1024 * 1024)
+        .aggregate_continuations()
+        .max_continuation_size(2_usize.pow(20));
+
+    rt::spawn(async move {
+        let mut client = Session::default();
+        let mut pcm_frames = PcmFrames::default();
+        while let Some(msg) = stream.next().await {
+            match msg {
+                Ok(AggregatedMessage::Binary(bin)) => {
+                    log::info!("Received binary websocket message");
+                    let response = match client.input() {
+                        Input::Recording { mime_type } => {
+                            let mime_type = mime_type.or_else(|| subprotocol_mime_type.clone());
+                            respond_to_recording(
+                                &pipeline,
+                                &mut client,
+                                move |pipeline, options| {
+                                    let audio = match &mime_type {
+                                        Some(mime_type) => Audio::Typed(&bin, mime_type),
+                                        None => Audio::Recording(&bin),
+                                    };
+                                    pipeline.transcribe(audio, options)
+                                },
+                            )
+                            .await
+                        }
+                        Input::Pcm(format) if bin.is_empty() => match pcm_frames.take() {
+                            Some(bytes) => {
+                                respond_to_recording(
+                                    &pipeline,
+                                    &mut client,
+                                    move |pipeline, options| {
+                                        pipeline.transcribe(Audio::RawPcm(&bytes, format), options)
+                                    },
+                                )
+                                .await
+                            }
+                            // We already told the client that the recording was too long
+                            None => continue,
+                        },
+                        Input::Pcm(format) => match pcm_frames.push(&bin, &format) {
+                            Ok(()) => continue,
+                            Err(err) => Err(err),
+                        },
+                    };
+                    match response {
+                        Ok(response) => session.text(response).await.unwrap(),
+                        Err(err) => send_error(&mut session, &err).await,
+                    }
+                }
+                Ok(AggregatedMessage::Text(text)) => {
+                    log::info!("Received text websocket message");
+                    let input = client.input();
+                    if let Err(err) = client.handle_control_message(&text) {
+                        send_error(&mut session, &err).await;
+                    }
+                    // Frames in the old format don't belong to the next recording
+                    if client.input() != input {
+                        pcm_frames = PcmFrames::default();
+                    }
+                }
+                Err(err) => {
+                    log::error!("Received a websocket message that caused error: {:?}", err)
+                }
+                _ => {}
+            }
+        }
+    });
+    Ok(res)
+}
+
+// e.g. "audio.webm" for audio/webm
+fn subprotocol_mime_type(protocol: &str) -> Option<String> {
+    let (kind, subtype) = protocol.split_once('.')?;
+    Some(format!("{kind}/{subtype}"))
+}
+
+// The first subprotocol that the client asked for that we can decode, if any
+fn choose_subprotocol(req: &HttpRequest, decoders: &DecoderRegistry) -> Option<String> {
+    let offered = req.headers().get(SEC_WEBSOCKET_PROTOCOL)?.to_str().ok()?;
+    offered
+        .split(',')
+        .map(str::trim)
+        .find(|protocol| {
+            subprotocol_mime_type(protocol)
+                .is_some_and(|mime_type| decoders.accepts_mime_type(&mime_type))
+        })
+        .map(str::to_owned)
+}
+
+// Raw PCM frames that a client has sent since its last recording ended
+#[derive(Default)]
+struct PcmFrames {
+    bytes: Vec<u8>,
+    too_long: bool,
+}
+
+impl PcmFrames {
+    // Errors (just once) when the recording gets too long, and drops the rest of it
+    fn push(&mut self, frame: &[u8], format: &RawPcm) -> anyhow::Result<()> {
+        if self.too_long {
+            return Ok(());
+        }
+        let max_bytes = (config::MAX_RAW_PCM_SECONDS * format.bytes_per_second())
+            .min(config::MAX_RAW_PCM_BYTES);
+        if self.bytes.len() + frame.len() > max_bytes {
+            self.bytes.clear();
+            self.too_long = true;
+            bail!(
+                "Raw PCM recordings can be at most {} seconds, or {} bytes, long",
+                config::MAX_RAW_PCM_SECONDS,
+                config::MAX_RAW_PCM_BYTES
+            );
+        }
+        self.bytes.extend_from_slice(frame);
+        Ok(())
+    }
+
+    // The whole recording, unless it was too long
+    fn take(&mut self) -> Option<Vec<u8>> {
+        let bytes = std::mem::take(&mut self.bytes);
+        if std::mem::take(&mut self.too_long) {
+            None
+        } else {
+            Some(bytes)
+        }
+    }
+}
+
+// Transcribe a recording with the client's settings, and render the response.
+// Transcription takes a while, so `transcribe` runs on a thread where blocking
+// is allowed, rather than holding up every other websocket on this worker.
+async fn respond_to_recording<F>(
+    pipeline: &web::Data<Pipeline>,
+    client: &mut Session,
+    transcribe: F,
+) -> anyhow::Result<String>
+where
+    F: FnOnce(&Pipeline, &DecodingOptions) -> anyhow::Result<Transcript> + Send + 'static,
+{
+    let request_settings = client.take_request_settings();
+    let options = client.decoding_options(&request_settings)?;
+    let pipeline = pipeline.clone();
+    let transcript = web::block(move || transcribe(&pipeline, &options)).await??;
+    client.record_transcription(&transcript.text);
+    let response_format = request_settings.response_format.unwrap_or_default();
+    Ok(response_format.render(&transcript))
+}
+
+// Errors are sent to the client as a JSON text message, e.g. {"error": "max_tokens must be at least 1"}
+async fn send_error(session: &mut actix_ws::Session, err: &anyhow::Error) {
+    log::error!("Could not complete the request: {:?}", err);
+    let message = serde_json::json!({ "error": format!("{err:#}") });
+    let _ = session.text(message.to_string()).await;
+}
+
+// Reload the models in the background, see the model_registry module
+async fn reload_models() -> HttpResponse {
+    match reload_models_in_background().await {
+        Ok(names) => HttpResponse::Ok().json(serde_json::json!({ "models": names })),
+        Err(err) => {
+            log::error!("Could not reload the models: {:?}", err);
+            HttpResponse::InternalServerError()
+                .json(serde_json::json!({ "error": format!("{err:#}") }))
+        }
+    }
+}
+
+async fn reload_models_in_background() -> anyhow::Result<Vec<String>> {
+    let registry = web::block(ModelRegistry::reload).await??;
+    Ok(registry.names().into_iter().map(str::to_owned).collect())
+}
+
+// There are no hangup signals outside of unix, so use POST /admin/reload there
+#[cfg(unix)]
+async fn reload_models_on_sighup() {
+    let mut hangups = match rt::signal::unix::signal(rt::signal::unix::SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(err) => {
+            log::error!("Could not listen for SIGHUP, so it will not reload the models: {err:?}");
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        log::info!("Received SIGHUP, reloading the models");
+        if let Err(err) = reload_models_in_background().await {
+            log::error!("Could not reload the models: {:?}", err);
+        }
+    }
+}
+
+/// Serve websocket clients on port 7025, and the admin endpoints on
+/// `config::ADMIN_PORT`, until the process is stopped
+pub async fn serve() -> std::io::Result<()> {
+    ModelRegistry::get()
+        .and_then(|registry| registry.download_all())
+        .map_err(std::io::Error::other)?;
+    DecodingOptions::deployment_defaults().map_err(std::io::Error::other)?;
+    #[cfg(unix)]
+    rt::spawn(reload_models_on_sighup());
+    let pipeline = web::Data::new(Pipeline::from_deployment());
+    let websockets = HttpServer::new(move || {
+        App::new()
+            .app_data(pipeline.clone())
+            .route("/", web::get().to(websocket_server))
+            .wrap(Logger::default())
+    })
+    .bind(("127.0.0.1", 7025))?
+    .run();
+    // Only reachable from this machine, whatever proxies the websocket port
+    let admin = HttpServer::new(|| {
+        App::new()
+            .route("/admin/reload", web::post().to(reload_models))
+            .wrap(Logger::default())
+    })
+    .workers(1)
+    .bind(("127.0.0.1", config::ADMIN_PORT))?
+    .run();
+    futures::try_join!(websockets, admin)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, sync::Arc};
+
+    use super::*;
+    use actix_http::ws;
+
+    use crate::{
+        transcriber::{Transcriber, WhisperTranscriber, mock::MockTranscriber},
+        transcript::Transcript,
+    };
+    use actix_web::{App, web::Bytes};
+    use futures_util::SinkExt as _;
+
+    fn start_server(transcriber: Arc<dyn Transcriber>) -> actix_test::TestServer {
+        let pipeline = web::Data::new(Pipeline::with_transcriber(transcriber));
+        actix_test::start(move || {
+            App::new()
+                .app_data(pipeline.clone())
+                .route("/", web::get().to(websocket_server))
+        })
+    }
+
+    fn recording() -> ws::Message {
+        ws::Message::Binary(
+            fs::read("./test_data/english/complete_book_of_cheese_mono.webm")
+                .unwrap()
+                .into(),
+        )
+    }
+
+    fn text(frame: ws::Frame) -> String {
+        match frame {
+            ws::Frame::Text(bytes) => String::from_utf8(bytes.to_vec()).unwrap(),
+            other => panic!("Expected a text frame, got {other:?}"),
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_websocket_transcribes_binary_message() {
+        let mut server = start_server(Arc::new(WhisperTranscriber::default()));
+        let mut socket = server.ws().await.unwrap();
+        socket
+            .send(ws::Message::Binary(
+                fs::read("./test_data/english/complete_book_of_cheese_mono.webm")
+                    .unwrap()
+                    .into(),
+            ))
+            .await
+            .unwrap();
+        let item = socket.next().await.unwrap().unwrap();
+        assert_eq!(
+            item,
+            ws::Frame::Text(Bytes::from_static(
+                b" The Complete Book of Cheese by Robert Carlton Brown"
+            ))
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_websocket_responds_with_each_transcription() {
+        let mut server = start_server(Arc::new(MockTranscriber::with_texts(&[" One", " Two"])));
+        let mut socket = server.ws().await.unwrap();
+        socket.send(recording()).await.unwrap();
+        assert_eq!(text(socket.next().await.unwrap().unwrap()), " One");
+        socket.send(recording()).await.unwrap();
+        assert_eq!(text(socket.next().await.unwrap().unwrap()), " Two");
+    }
+
+    #[actix_web::test]
+    async fn test_websocket_applies_session_and_request_settings() {
+        let transcriber = Arc::new(MockTranscriber::with_texts(&[" One", " Two"]));
+        let mut server = start_server(transcriber.clone());
+        let mut socket = server.ws().await.unwrap();
+        socket
+            .send(ws::Message::Text(
+                r#"{"session": {"prompt": "author, title", "response_format": "json"}}"#.into(),
+            ))
+            .await
+            .unwrap();
+        socket
+            .send(ws::Message::Text(
+                r#"{"request": {"max_tokens": 10}}"#.into(),
+            ))
+            .await
+            .unwrap();
+        socket.send(recording()).await.unwrap();
+        let response: serde_json::Value =
+            serde_json::from_str(&text(socket.next().await.unwrap().unwrap())).unwrap();
+        assert_eq!(response["text"], " One");
+        assert_eq!(response["outcome"], "recognized");
+        socket.send(recording()).await.unwrap();
+        socket.next().await.unwrap().unwrap();
+
+        let requests = transcriber.requests.lock().unwrap();
+        assert_eq!(requests[0].prompt, Some("author, title".to_owned()));
+        assert_eq!(requests[0].max_tokens, Some(10));
+        assert_eq!(requests[1].prompt, Some("author, title".to_owned()));
+        assert_eq!(requests[1].max_tokens, None);
+    }
+
+    #[actix_web::test]
+    async fn test_websocket_responds_with_an_error_for_invalid_settings() {
+        let mut server = start_server(Arc::new(MockTranscriber::with_texts(&[])));
+        let mut socket = server.ws().await.unwrap();
+        socket
+            .send(ws::Message::Text(
+                r#"{"request": {"max_tokens": 0}}"#.into(),
+            ))
+            .await
+            .unwrap();
+        let response: serde_json::Value =
+            serde_json::from_str(&text(socket.next().await.unwrap().unwrap())).unwrap();
+        assert_eq!(response["error"], "max_tokens must be at least 1");
+    }
+
+    #[actix_web::test]
+    async fn test_websocket_responds_with_an_error_for_audio_it_cannot_decode() {
+        let mut server = start_server(Arc::new(MockTranscriber::with_texts(&[" Unused", " Two"])));
+        let mut socket = server.ws().await.unwrap();
+        socket
+            .send(ws::Message::Binary(Bytes::from_static(b"not a webm file")))
+            .await
+            .unwrap();
+        let response = text(socket.next().await.unwrap().unwrap());
+        assert!(response.starts_with(r#"{"error":"#));
+
+        // The connection is still usable afterward
+        socket.send(recording()).await.unwrap();
+        assert_eq!(text(socket.next().await.unwrap().unwrap()), " Unused");
+    }
+
+    #[actix_web::test]
+    async fn test_websocket_accepts_raw_pcm_frames() {
+        let transcriber = Arc::new(MockTranscriber::with_texts(&[" One", " Two"]));
+        let mut server = start_server(transcriber.clone());
+        let mut socket = server.ws().await.unwrap();
+        socket
+            .send(ws::Message::Text(
+                r#"{"session": {"input": {"format": "pcm", "sample_rate": 48000, "channels": 1, "sample_format": "s16le"}}}"#.into(),
+            ))
+            .await
+            .unwrap();
+        // A second of silence, in AudioWorklet sized frames of 128 samples
+        for _ in 0..375 {
+            socket
+                .send(ws::Message::Binary(Bytes::from_static(&[0; 256])))
+                .await
+                .unwrap();
+        }
+        socket
+            .send(ws::Message::Binary(Bytes::new()))
+            .await
+            .unwrap();
+        assert_eq!(text(socket.next().await.unwrap().unwrap()), " One");
+
+        // The next recording starts from scratch
+        socket
+            .send(ws::Message::Binary(Bytes::from_static(&[0; 960])))
+            .await
+            .unwrap();
+        socket
+            .send(ws::Message::Binary(Bytes::new()))
+            .await
+            .unwrap();
+        assert_eq!(text(socket.next().await.unwrap().unwrap()), " Two");
+        // A second, and then 10 ms
+        let rate = config::AUDIO_DECODE_SAMPLE_RATE as usize;
+        assert_eq!(
+            *transcriber.sample_counts.lock().unwrap(),
+            vec![rate, rate / 100]
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_websocket_responds_with_an_error_for_raw_pcm_that_is_too_long() {
+        let mut server = start_server(Arc::new(MockTranscriber::with_texts(&[" One"])));
+        let mut socket = server.ws().await.unwrap();
+        socket
+            .send(ws::Message::Text(
+                r#"{"session": {"input": {"format": "pcm", "sample_rate": 8000, "channels": 1, "sample_format": "s16le"}}}"#.into(),
+            ))
+            .await
+            .unwrap();
+        let second = vec![0; 16_000];
+        for _ in 0..=config::MAX_RAW_PCM_SECONDS {
+            socket
+                .send(ws::Message::Binary(second.clone().into()))
+                .await
+                .unwrap();
+        }
+        let response: serde_json::Value =
+            serde_json::from_str(&text(socket.next().await.unwrap().unwrap())).unwrap();
+        assert!(response["error"].as_str().unwrap().contains("at most"));
+
+        // Ending the recording that was too long doesn't transcribe it, but
+        // the next one is fine
+        socket
+            .send(ws::Message::Binary(Bytes::new()))
+            .await
+            .unwrap();
+        socket
+            .send(ws::Message::Binary(second.into()))
+            .await
+            .unwrap();
+        socket
+            .send(ws::Message::Binary(Bytes::new()))
+            .await
+            .unwrap();
+        assert_eq!(text(socket.next().await.unwrap().unwrap()), " One");
+    }
+
+    #[test]
+    fn it_limits_raw_pcm_to_a_number_of_bytes_whatever_the_format() {
+        let format = RawPcm {
+            sample_rate: config::MAX_RAW_PCM_SAMPLE_RATE,
+            channels: config::MAX_RAW_PCM_CHANNELS,
+            sample_format: crate::SampleFormat::F32le,
+        };
+        let mut frames = PcmFrames::default();
+        let frame = vec![0; 1024 * 1024];
+        let pushed = (0..100)
+            .take_while(|_| frames.push(&frame, &format).is_ok())
+            .count();
+        assert_eq!(pushed, config::MAX_RAW_PCM_BYTES / frame.len());
+        assert_eq!(frames.take(), None);
+    }
+
+    #[actix_web::test]
+    async fn test_websocket_drops_raw_pcm_frames_when_the_input_changes() {
+        let transcriber = Arc::new(MockTranscriber::with_texts(&[" One"]));
+        let mut server = start_server(transcriber.clone());
+        let mut socket = server.ws().await.unwrap();
+        let pcm = r#"{"session": {"input": {"format": "pcm", "sample_rate": 16000, "channels": 1, "sample_format": "s16le"}}}"#;
+        socket.send(ws::Message::Text(pcm.into())).await.unwrap();
+        socket
+            .send(ws::Message::Binary(Bytes::from_static(&[0; 3_200])))
+            .await
+            .unwrap();
+        // Back to recordings, and then to PCM again
+        socket
+            .send(ws::Message::Text(
+                r#"{"session": {"input": {"format": "recording"}}}"#.into(),
+            ))
+            .await
+            .unwrap();
+        socket.send(ws::Message::Text(pcm.into())).await.unwrap();
+        socket
+            .send(ws::Message::Binary(Bytes::from_static(&[0; 320])))
+            .await
+            .unwrap();
+        socket
+            .send(ws::Message::Binary(Bytes::new()))
+            .await
+            .unwrap();
+        assert_eq!(text(socket.next().await.unwrap().unwrap()), " One");
+        // Just the 10 ms from after the switch back
+        assert_eq!(
+            *transcriber.sample_counts.lock().unwrap(),
+            vec![config::AUDIO_DECODE_SAMPLE_RATE as usize / 100]
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_websocket_takes_the_recording_format_from_the_subprotocol() {
+        let server = start_server(Arc::new(MockTranscriber::with_texts(&[" One"])));
+        let (response, mut socket) = awc::Client::new()
+            .ws(server.url("/"))
+            .protocols(["chat", "audio.ogg"])
+            .connect()
+            .await
+            .unwrap();
+        assert_eq!(
+            response.headers().get(SEC_WEBSOCKET_PROTOCOL).unwrap(),
+            "audio.ogg"
+        );
+        // We can't tell what this is from its first bytes, so we try it as Ogg
+        socket
+            .send(ws::Message::Binary(Bytes::from_static(b"not a webm file")))
+            .await
+            .unwrap();
+        let response = text(socket.next().await.unwrap().unwrap());
+        assert!(response.contains("Ogg"), "{response}");
+
+        // Recordings that we can recognize are decoded whatever the subprotocol says
+        socket.send(recording()).await.unwrap();
+        assert_eq!(text(socket.next().await.unwrap().unwrap()), " One");
+    }
+
+    #[actix_web::test]
+    async fn test_websocket_names_the_format_it_cannot_decode() {
+        let mut server = start_server(Arc::new(MockTranscriber::with_texts(&[])));
+        let mut socket = server.ws().await.unwrap();
+        socket
+            .send(ws::Message::Text(
+                r#"{"session": {"input": {"format": "recording", "mime_type": "audio/x-unknown"}}}"#
+                    .into(),
+            ))
+            .await
+            .unwrap();
+        socket
+            .send(ws::Message::Binary(Bytes::from_static(b"\0\0\0\0")))
+            .await
+            .unwrap();
+        let response: serde_json::Value =
+            serde_json::from_str(&text(socket.next().await.unwrap().unwrap())).unwrap();
+        assert_eq!(
+            response["error"],
+            "Unsupported audio format: a recording that starts with 00 00 00 00 (which the client called audio/x-unknown)"
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_websocket_responds_with_an_error_when_transcription_fails() {
+        let transcriber = MockTranscriber::new(vec![
+            Err("out of memory".to_owned()),
+            Ok(Transcript {
+                text: " Two".to_owned(),
+                ..Default::default()
+            }),
+        ]);
+        let mut server = start_server(Arc::new(transcriber));
+        let mut socket = server.ws().await.unwrap();
+        socket.send(recording()).await.unwrap();
+        assert_eq!(
+            text(socket.next().await.unwrap().unwrap()),
+            r#"{"error":"out of memory"}"#
+        );
+        socket.send(recording()).await.unwrap();
+        assert_eq!(text(socket.next().await.unwrap().unwrap()), " Two");
+    }
+}
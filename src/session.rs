@@ -0,0 +1,387 @@
+// This module keeps track of the settings that a websocket client
+// has chosen, either for their whole session or just for their next
+// recording.
+//
+// Clients choose settings by sending a text websocket message containing JSON:
+//
+//   {"session": {"prompt": "Princeton University Library catalog, author, title"}}
+//   {"request": {"prompt": "Mark Twain"}}
+//   {"request": {"grammar": "(next|previous) page", "temperatures": [0.0]}}
+//   {"session": {"response_format": "json"}}
+//   {"request": {"language": "en"}}
+//   {"session": {"model": "turbo"}}
+//   {"session": {"channel": "loudest"}}
+//   {"session": {"input": {"format": "pcm", "sample_rate": 48000, "channels": 1, "sample_format": "f32le"}}}
+//
+// Session settings apply to every recording sent after them.  Request settings
+// apply only to the next recording, and take precedence over the session settings.
+// Any settings that the client doesn't choose come from the deployment's
+// DecodingOptions.
+
+use anyhow::Result;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+use crate::{
+    audio::Input, decoding_options::DecodingOptions, model_registry::ModelRegistry,
+    transcript::ResponseFormat,
+};
+
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+pub struct Settings {
+    // Whether to also prime the model with the previous transcription in this session
+    pub condition_on_previous: Option<bool>,
+    // Whether to respond with just the text, or JSON that includes word confidences
+    pub response_format: Option<ResponseFormat>,
+    // Whether the client sends whole recordings, or streams raw PCM frames
+    pub input: Option<Input>,
+    // The rest of the settings override the DecodingOptions of the same name.
+    // We keep them as JSON, and DecodingOptions checks them when they're applied,
+    // so that every option can be set without being listed here as well.
+    #[serde(flatten)]
+    overrides: Map<String, Value>,
+}
+
+impl Settings {
+    // Any setting in `overrides` replaces the setting in self
+    pub fn merge(&self, overrides: &Settings) -> Settings {
+        let mut merged = self.clone();
+        merged.condition_on_previous = overrides
+            .condition_on_previous
+            .or(self.condition_on_previous);
+        merged.response_format = overrides.response_format.or(self.response_format);
+        merged.input = overrides.input.clone().or(self.input.clone());
+        // A null means the client didn't choose, like a missing setting
+        merged.overrides.extend(
+            overrides
+                .overrides
+                .iter()
+                .filter(|(_, value)| !value.is_null())
+                .map(|(name, value)| (name.clone(), value.clone())),
+        );
+        merged
+    }
+
+    // Errors on settings that aren't DecodingOptions, or are the wrong type
+    fn apply(&self, defaults: &DecodingOptions) -> Result<DecodingOptions> {
+        let mut options = serde_json::to_value(defaults)?;
+        if let Value::Object(options) = &mut options {
+            options.extend(self.overrides.clone());
+        }
+        Ok(serde_json::from_value(options)?)
+    }
+}
+
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ControlMessage {
+    Session(Settings),
+    Request(Settings),
+}
+
+#[derive(Debug)]
+pub struct Session {
+    defaults: DecodingOptions,
+    settings: Settings,
+    next_request: Option<Settings>,
+    previous_transcription: Option<String>,
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        // serve() has already stopped if the deployment's defaults don't load
+        Session::new(
+            DecodingOptions::deployment_defaults()
+                .cloned()
+                .unwrap_or_default(),
+        )
+    }
+}
+
+impl Session {
+    pub fn new(defaults: DecodingOptions) -> Session {
+        Session {
+            defaults,
+            settings: Settings::default(),
+            next_request: None,
+            previous_transcription: None,
+        }
+    }
+
+    // Settings that would lead to invalid DecodingOptions are rejected, and leave the session unchanged
+    pub fn handle_control_message(&mut self, message: &str) -> Result<()> {
+        let message: ControlMessage = serde_json::from_str(message)?;
+        let (ControlMessage::Session(settings) | ControlMessage::Request(settings)) = &message;
+        if let Some(Input::Pcm(format)) = &settings.input {
+            format.validate()?;
+        }
+        match message {
+            ControlMessage::Session(settings) => {
+                let merged = self.settings.merge(&settings);
+                self.decoding_options(&merged)?;
+                self.settings = merged;
+            }
+            ControlMessage::Request(settings) => {
+                self.decoding_options(&self.settings.merge(&settings))?;
+                self.next_request = Some(settings);
+            }
+        }
+        Ok(())
+    }
+
+    // How the client will send its next recording, without using up the request settings
+    pub fn input(&self) -> Input {
+        let request = self
+            .next_request
+            .as_ref()
+            .and_then(|settings| settings.input.clone());
+        request
+            .or_else(|| self.settings.input.clone())
+            .unwrap_or_default()
+    }
+
+    // The settings for the next recording.  Request settings are used up by this call.
+    pub fn take_request_settings(&mut self) -> Settings {
+        match self.next_request.take() {
+            Some(overrides) => self.settings.merge(&overrides),
+            None => self.settings.clone(),
+        }
+    }
+
+    pub fn decoding_options(&self, settings: &Settings) -> Result<DecodingOptions> {
+        let mut options = settings.apply(&self.defaults)?;
+        options.prompt = self.prompt(options.prompt.as_deref(), settings);
+        options.validate()?;
+        if let Some(model) = &options.model {
+            ModelRegistry::get()?.named(model)?;
+        }
+        Ok(options)
+    }
+
+    fn prompt(&self, prompt: Option<&str>, settings: &Settings) -> Option<String> {
+        let previous = if settings.condition_on_previous.unwrap_or(false) {
+            self.previous_transcription.as_deref()
+        } else {
+            None
+        };
+        let parts: Vec<&str> = [prompt, previous]
+            .into_iter()
+            .flatten()
+            .map(str::trim)
+            .filter(|part| !part.is_empty())
+            .collect();
+        if parts.is_empty() {
+            None
+        } else {
+            Some(parts.join(" "))
+        }
+    }
+
+    pub fn record_transcription(&mut self, transcription: &str) {
+        self.previous_transcription = Some(transcription.to_owned());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::ChannelSelection;
+
+    fn next_options(session: &mut Session) -> DecodingOptions {
+        let settings = session.take_request_settings();
+        session.decoding_options(&settings).unwrap()
+    }
+
+    #[test]
+    fn it_uses_the_session_prompt_for_every_request() {
+        let mut session = Session::new(DecodingOptions::default());
+        session
+            .handle_control_message(r#"{"session": {"prompt": "author, title"}}"#)
+            .unwrap();
+        for _ in 0..2 {
+            assert_eq!(
+                next_options(&mut session).prompt,
+                Some("author, title".to_owned())
+            );
+        }
+    }
+
+    #[test]
+    fn it_uses_the_request_prompt_only_once() {
+        let mut session = Session::new(DecodingOptions::default());
+        session
+            .handle_control_message(r#"{"session": {"prompt": "author, title"}}"#)
+            .unwrap();
+        session
+            .handle_control_message(r#"{"request": {"prompt": "Mark Twain"}}"#)
+            .unwrap();
+        assert_eq!(
+            next_options(&mut session).prompt,
+            Some("Mark Twain".to_owned())
+        );
+        assert_eq!(
+            next_options(&mut session).prompt,
+            Some("author, title".to_owned())
+        );
+    }
+
+    #[test]
+    fn it_can_condition_on_the_previous_transcription() {
+        let mut session = Session::new(DecodingOptions::default());
+        session
+            .handle_control_message(
+                r#"{"session": {"prompt": "author, title", "condition_on_previous": true}}"#,
+            )
+            .unwrap();
+        session.record_transcription(" The Complete Book of Cheese");
+        assert_eq!(
+            next_options(&mut session).prompt,
+            Some("author, title The Complete Book of Cheese".to_owned())
+        );
+    }
+
+    #[test]
+    fn it_has_no_prompt_by_default() {
+        let mut session = Session::new(DecodingOptions::default());
+        session.record_transcription("The Complete Book of Cheese");
+        assert_eq!(next_options(&mut session).prompt, None);
+    }
+
+    #[test]
+    fn it_starts_from_the_deployment_defaults() {
+        let defaults = DecodingOptions {
+            temperatures: vec![0.0, 0.5],
+            max_tokens: Some(64),
+            ..Default::default()
+        };
+        let mut session = Session::new(defaults);
+        session
+            .handle_control_message(r#"{"request": {"max_tokens": 16}}"#)
+            .unwrap();
+        let options = next_options(&mut session);
+        assert_eq!(options.temperatures, vec![0.0, 0.5]);
+        assert_eq!(options.max_tokens, Some(16));
+        assert_eq!(next_options(&mut session).max_tokens, Some(64));
+    }
+
+    #[test]
+    fn it_can_override_every_decoding_option() {
+        let options = DecodingOptions {
+            model: None,
+            language: Some("pt".to_owned()),
+            channel: ChannelSelection::Channel(1),
+            temperatures: vec![0.0, 0.3],
+            logprob_threshold: -0.5,
+            no_speech_threshold: 0.4,
+            compression_ratio_threshold: 2.0,
+            max_tokens: Some(32),
+            seed: 42,
+            suppress_tokens: vec![7],
+            prompt: Some("author, title".to_owned()),
+            grammar: Some("next page".to_owned()),
+            min_confidence: 0.7,
+            min_snr_db: 3.0,
+        };
+        let mut session = Session::new(DecodingOptions::default());
+        let message = serde_json::json!({ "session": options });
+        session
+            .handle_control_message(&message.to_string())
+            .unwrap();
+        assert_eq!(next_options(&mut session), options);
+    }
+
+    #[test]
+    fn it_ignores_settings_that_are_null() {
+        let mut session = Session::new(DecodingOptions::default());
+        session
+            .handle_control_message(r#"{"session": {"max_tokens": 16}}"#)
+            .unwrap();
+        session
+            .handle_control_message(r#"{"request": {"max_tokens": null}}"#)
+            .unwrap();
+        assert_eq!(next_options(&mut session).max_tokens, Some(16));
+    }
+
+    #[test]
+    fn it_can_choose_a_response_format() {
+        let mut session = Session::new(DecodingOptions::default());
+        assert_eq!(session.take_request_settings().response_format, None);
+        session
+            .handle_control_message(r#"{"session": {"response_format": "json"}}"#)
+            .unwrap();
+        assert_eq!(
+            session.take_request_settings().response_format,
+            Some(ResponseFormat::Json)
+        );
+        assert!(
+            session
+                .handle_control_message(r#"{"session": {"response_format": "xml"}}"#)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn it_can_switch_to_raw_pcm_input() {
+        let mut session = Session::new(DecodingOptions::default());
+        assert_eq!(session.input(), Input::default());
+        session
+            .handle_control_message(
+                r#"{"session": {"input": {"format": "pcm", "sample_rate": 48000, "channels": 2, "sample_format": "s16le"}}}"#,
+            )
+            .unwrap();
+        assert!(matches!(session.input(), Input::Pcm(format) if format.channels == 2));
+        session
+            .handle_control_message(
+                r#"{"request": {"input": {"format": "recording", "mime_type": "audio/ogg"}}}"#,
+            )
+            .unwrap();
+        assert_eq!(
+            session.input(),
+            Input::Recording {
+                mime_type: Some("audio/ogg".to_owned())
+            }
+        );
+        session.take_request_settings();
+        assert!(matches!(session.input(), Input::Pcm(_)));
+
+        assert!(
+            session
+                .handle_control_message(
+                    r#"{"session": {"input": {"format": "pcm", "sample_rate": 0, "channels": 1, "sample_format": "s16le"}}}"#,
+                )
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn it_errors_on_unknown_settings() {
+        let mut session = Session::new(DecodingOptions::default());
+        assert!(
+            session
+                .handle_control_message(r#"{"session": {"promt": "typo"}}"#)
+                .is_err()
+        );
+        assert!(session.handle_control_message("not json").is_err());
+    }
+
+    #[test]
+    fn it_errors_on_invalid_settings() {
+        let mut session = Session::new(DecodingOptions::default());
+        assert!(
+            session
+                .handle_control_message(r#"{"request": {"grammar": "(next page"}}"#)
+                .is_err()
+        );
+        assert!(
+            session
+                .handle_control_message(r#"{"session": {"temperatures": []}}"#)
+                .is_err()
+        );
+        assert!(
+            session
+                .handle_control_message(r#"{"session": {"model": "no such model"}}"#)
+                .is_err()
+        );
+        assert_eq!(session.take_request_settings(), Settings::default());
+    }
+}
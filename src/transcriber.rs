@@ -0,0 +1,155 @@
+// This module is responsible for hiding the details of how we transcribe
+// audio from the websocket server, so that the server can be tested with
+// a fast, deterministic mock instead of the real model.
+
+use std::sync::Arc;
+
+use anyhow::Result;
+
+use crate::{
+    audio, config, decoding_options::DecodingOptions, feature_extraction::extract_features,
+    model_registry::ModelRegistry, transcript::Transcript, transcription,
+};
+
+/// A way of transcribing audio, see [`Pipeline::with_transcriber`](crate::Pipeline::with_transcriber).
+pub trait Transcriber: Send + Sync {
+    /// Transcribe mono PCM samples at `sample_rate` (in Hz).  A [`Pipeline`](crate::Pipeline)
+    /// hands over samples at the rate that whisper models need.
+    fn transcribe(
+        &self,
+        samples: Vec<f32>,
+        sample_rate: u32,
+        options: &DecodingOptions,
+    ) -> Result<Transcript>;
+}
+
+// Transcribes with whichever whisper model the ModelRegistry chooses.  By default,
+// that's the deployment's registry, which can be reloaded at any time.
+#[derive(Default)]
+pub struct WhisperTranscriber {
+    registry: Option<Arc<ModelRegistry>>,
+}
+
+impl WhisperTranscriber {
+    pub fn with_models(registry: Arc<ModelRegistry>) -> WhisperTranscriber {
+        WhisperTranscriber {
+            registry: Some(registry),
+        }
+    }
+}
+
+impl Transcriber for WhisperTranscriber {
+    fn transcribe(
+        &self,
+        samples: Vec<f32>,
+        sample_rate: u32,
+        options: &DecodingOptions,
+    ) -> Result<Transcript> {
+        let samples = audio::resample(&samples, sample_rate, config::AUDIO_DECODE_SAMPLE_RATE);
+        let duration_secs = samples.len() as f64 / config::AUDIO_DECODE_SAMPLE_RATE as f64;
+        let registry = match &self.registry {
+            Some(registry) => registry.clone(),
+            None => ModelRegistry::get()?,
+        };
+        let model = registry.route(
+            options.model.as_deref(),
+            options.language.as_deref(),
+            duration_secs,
+        )?;
+        log::info!(
+            "Transcribing {duration_secs:.1}s of audio with model {}",
+            model.name()
+        );
+        let repo = model.try_repo()?;
+        let features = extract_features(samples, repo)?;
+        let mut transcript = transcription::transcribe(features, repo, options)?;
+        // The last segment includes the padding that whisper adds to the end of the recording
+        for segment in &mut transcript.segments {
+            segment.end = segment.end.min(duration_secs);
+        }
+        Ok(transcript)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod mock {
+    use std::{collections::VecDeque, sync::Mutex};
+
+    use anyhow::anyhow;
+
+    use super::*;
+
+    // Responds to each request with the next item from its script, and remembers
+    // the options and number of samples of each request so that tests can check them
+    pub struct MockTranscriber {
+        script: Mutex<VecDeque<Result<Transcript, String>>>,
+        pub requests: Mutex<Vec<DecodingOptions>>,
+        pub sample_counts: Mutex<Vec<usize>>,
+    }
+
+    impl MockTranscriber {
+        pub fn new(script: Vec<Result<Transcript, String>>) -> MockTranscriber {
+            MockTranscriber {
+                script: Mutex::new(script.into()),
+                requests: Mutex::new(vec![]),
+                sample_counts: Mutex::new(vec![]),
+            }
+        }
+
+        // A transcriber that always responds with these texts, in order
+        pub fn with_texts(texts: &[&str]) -> MockTranscriber {
+            MockTranscriber::new(
+                texts
+                    .iter()
+                    .map(|text| {
+                        Ok(Transcript {
+                            text: text.to_string(),
+                            ..Default::default()
+                        })
+                    })
+                    .collect(),
+            )
+        }
+    }
+
+    impl Transcriber for MockTranscriber {
+        fn transcribe(
+            &self,
+            samples: Vec<f32>,
+            _sample_rate: u32,
+            options: &DecodingOptions,
+        ) -> Result<Transcript> {
+            self.requests.lock().unwrap().push(options.clone());
+            self.sample_counts.lock().unwrap().push(samples.len());
+            match self.script.lock().unwrap().pop_front() {
+                Some(Ok(transcript)) => Ok(transcript),
+                Some(Err(message)) => Err(anyhow!(message)),
+                None => Err(anyhow!("MockTranscriber has run out of scripted responses")),
+            }
+        }
+    }
+
+    #[test]
+    fn it_follows_its_script() {
+        let mock = MockTranscriber::new(vec![
+            Ok(Transcript {
+                text: " Hello".to_owned(),
+                ..Default::default()
+            }),
+            Err("model exploded".to_owned()),
+        ]);
+        let options = DecodingOptions::default();
+        assert_eq!(
+            mock.transcribe(vec![], 16_000, &options).unwrap().text,
+            " Hello"
+        );
+        assert_eq!(
+            mock.transcribe(vec![], 16_000, &options)
+                .unwrap_err()
+                .to_string(),
+            "model exploded"
+        );
+        assert!(mock.transcribe(vec![], 16_000, &options).is_err());
+        assert_eq!(mock.requests.lock().unwrap().len(), 3);
+    }
+}
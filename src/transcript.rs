@@ -0,0 +1,391 @@
+// This module is responsible for the result of a transcription:
+// its text, how confident the model was in each token and word (so that
+// a client can point out the words that the patron may want to correct),
+// and whether the client should ask the patron to try again.
+
+use anyhow::Result;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use tokenizers::Tokenizer;
+
+/// The result of transcribing a recording
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Transcript {
+    pub outcome: Outcome,
+    pub text: String,
+    pub tokens: Vec<TokenConfidence>,
+    pub words: Vec<WordConfidence>,
+    pub segments: Vec<Segment>,
+    /// How many damaged packets of the recording we had to skip or conceal
+    pub dropped_packets: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TokenConfidence {
+    pub text: String,
+    /// The natural log of the probability the model gave this token
+    pub logprob: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct WordConfidence {
+    pub word: String,
+    /// Where the word starts in the transcript's text, in characters
+    pub offset: usize,
+    /// The average probability of the word's tokens, between 0 and 1
+    pub confidence: f64,
+}
+
+/// Whisper transcribes a recording in segments of up to 30 seconds
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Segment {
+    /// When the segment starts and ends in the recording, in seconds
+    pub start: f64,
+    pub end: f64,
+    pub text: String,
+}
+
+impl Transcript {
+    // `tokens` are the text tokens the model chose, along with their log probabilities
+    pub(crate) fn new(tokenizer: &Tokenizer, tokens: &[(u32, f64)]) -> Result<Transcript> {
+        let pieces = pieces(tokenizer, tokens)?;
+        Ok(Transcript {
+            outcome: Outcome::default(),
+            text: pieces.iter().map(|p| p.text.as_str()).collect(),
+            words: words(&pieces),
+            tokens: pieces,
+            segments: vec![],
+            dropped_packets: 0,
+        })
+    }
+
+    /// The average confidence of the words, or None if there are no words
+    pub fn confidence(&self) -> Option<f64> {
+        if self.words.is_empty() {
+            return None;
+        }
+        Some(self.words.iter().map(|w| w.confidence).sum::<f64>() / self.words.len() as f64)
+    }
+
+    // A noisy recording explains why we could not recognize anything, so we
+    // tell the client.  But if we did recognize the query, we don't second-guess it.
+    pub(crate) fn check_noise(&mut self, snr_db: f64, min_snr_db: f64) {
+        if self.outcome != Outcome::Recognized && snr_db < min_snr_db {
+            self.outcome = Outcome::TooNoisy;
+        }
+    }
+}
+
+/// Whether we recognized a query, or why not, so that the client can
+/// ask the patron to try again rather than searching for an empty or garbled query
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    #[default]
+    Recognized,
+    /// The recording was silent, or the model did not hear anyone speaking
+    NoSpeech,
+    /// The model heard something, but is not sure what
+    LowConfidence,
+    /// There was too much background noise
+    TooNoisy,
+}
+
+impl Outcome {
+    pub(crate) fn classify(
+        transcript: &Transcript,
+        hallucinated: bool,
+        min_confidence: f64,
+    ) -> Outcome {
+        match transcript.confidence() {
+            None if hallucinated => Outcome::LowConfidence,
+            None => Outcome::NoSpeech,
+            Some(confidence) if confidence < min_confidence => Outcome::LowConfidence,
+            Some(_) => Outcome::Recognized,
+        }
+    }
+}
+
+// Decode each token into text.  A character can be split across more than one
+// byte-level token, so those tokens are combined into a single piece.
+fn pieces(tokenizer: &Tokenizer, tokens: &[(u32, f64)]) -> Result<Vec<TokenConfidence>> {
+    let mut pieces = vec![];
+    let mut pending_ids = vec![];
+    let mut pending_logprob = 0.0;
+    for (i, &(id, logprob)) in tokens.iter().enumerate() {
+        pending_ids.push(id);
+        pending_logprob += logprob;
+        let text = tokenizer
+            .decode(&pending_ids, true)
+            .map_err(anyhow::Error::msg)?;
+        let is_last = i == tokens.len() - 1;
+        if text.contains(char::REPLACEMENT_CHARACTER) && !is_last {
+            continue;
+        }
+        if !text.is_empty() {
+            pieces.push(TokenConfidence {
+                text,
+                logprob: pending_logprob,
+            });
+        }
+        pending_ids.clear();
+        pending_logprob = 0.0;
+    }
+    Ok(pieces)
+}
+
+// A new word starts at each piece that begins with whitespace.  Pieces that
+// don't (like the rest of a long word, or punctuation) belong to the word before.
+fn words(pieces: &[TokenConfidence]) -> Vec<WordConfidence> {
+    let mut words: Vec<(String, usize, Vec<f64>)> = vec![];
+    let mut offset = 0;
+    for piece in pieces {
+        let starts_word = piece.text.starts_with(char::is_whitespace);
+        match words.last_mut() {
+            Some((word, _, probabilities)) if !starts_word => {
+                word.push_str(&piece.text);
+                probabilities.push(piece.logprob.exp());
+            }
+            _ => {
+                let leading_whitespace = piece.text.len() - piece.text.trim_start().len();
+                words.push((
+                    piece.text.trim_start().to_owned(),
+                    offset + piece.text[..leading_whitespace].chars().count(),
+                    vec![piece.logprob.exp()],
+                ));
+            }
+        }
+        offset += piece.text.chars().count();
+    }
+    words
+        .into_iter()
+        .filter(|(word, _, _)| !word.is_empty())
+        .map(|(word, offset, probabilities)| WordConfidence {
+            word,
+            offset,
+            confidence: probabilities.iter().sum::<f64>() / probabilities.len() as f64,
+        })
+        .collect()
+}
+
+/// How the server responds to the client with a transcript, or how the
+/// transcribe command prints it
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum ResponseFormat {
+    /// Just the text of the transcript, or `{"outcome": ...}` when it wasn't recognized
+    #[default]
+    Text,
+    /// The whole transcript as JSON, including confidences
+    Json,
+    /// Subtitles, with a cue for each segment
+    Srt,
+    Vtt,
+}
+
+impl ResponseFormat {
+    /// Render the transcript as text, JSON, or subtitles
+    pub fn render(&self, transcript: &Transcript) -> String {
+        match self {
+            ResponseFormat::Text if transcript.outcome != Outcome::Recognized => {
+                // So that the client can tell this from a transcription, like an error
+                serde_json::json!({ "outcome": transcript.outcome }).to_string()
+            }
+            ResponseFormat::Text => transcript.text.clone(),
+            ResponseFormat::Json => serde_json::to_string(transcript).unwrap(),
+            ResponseFormat::Srt => transcript
+                .segments
+                .iter()
+                .enumerate()
+                .map(|(i, segment)| {
+                    format!(
+                        "{}\n{} --> {}\n{}\n\n",
+                        i + 1,
+                        timestamp(segment.start, ','),
+                        timestamp(segment.end, ','),
+                        segment.text.trim()
+                    )
+                })
+                .collect(),
+            ResponseFormat::Vtt => {
+                let cues: String = transcript
+                    .segments
+                    .iter()
+                    .map(|segment| {
+                        format!(
+                            "{} --> {}\n{}\n\n",
+                            timestamp(segment.start, '.'),
+                            timestamp(segment.end, '.'),
+                            segment.text.trim()
+                        )
+                    })
+                    .collect();
+                format!("WEBVTT\n\n{cues}")
+            }
+        }
+    }
+}
+
+// e.g. 00:01:02,500 for SRT, or 00:01:02.500 for VTT
+fn timestamp(seconds: f64, decimal_separator: char) -> String {
+    let millis = (seconds * 1000.0).round() as u64;
+    format!(
+        "{:02}:{:02}:{:02}{decimal_separator}{:03}",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        millis % 1000
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use tokenizers::{decoders::byte_level::ByteLevel, models::bpe::BPE};
+
+    use super::*;
+
+    // A tiny byte-level tokenizer like the one whisper uses
+    fn tokenizer() -> Tokenizer {
+        let vocab: HashMap<String, u32> = ["ĠThe", "Ġcomp", "lete", "Ġbook", ",", "Ġcaf", "Ã", "©"]
+            .iter()
+            .enumerate()
+            .map(|(id, token)| (token.to_string(), id as u32))
+            .collect();
+        let bpe = BPE::builder()
+            .vocab_and_merges(vocab, vec![])
+            .build()
+            .unwrap();
+        let mut tokenizer = Tokenizer::new(bpe);
+        tokenizer.with_decoder(Some(ByteLevel::default()));
+        tokenizer
+    }
+
+    #[test]
+    fn it_groups_tokens_into_words() {
+        let transcript =
+            Transcript::new(&tokenizer(), &[(0, -0.1), (1, -0.2), (2, -2.0), (4, -0.1)]).unwrap();
+        assert_eq!(transcript.text, " The complete,");
+        assert_eq!(transcript.tokens.len(), 4);
+        assert_eq!(transcript.words.len(), 2);
+        assert_eq!(transcript.words[0].word, "The");
+        assert_eq!(transcript.words[0].offset, 1);
+        assert!((transcript.words[0].confidence - (-0.1f64).exp()).abs() < 1e-9);
+        assert_eq!(transcript.words[1].word, "complete,");
+        assert_eq!(transcript.words[1].offset, 5);
+        let expected = ((-0.2f64).exp() + (-2.0f64).exp() + (-0.1f64).exp()) / 3.0;
+        assert!((transcript.words[1].confidence - expected).abs() < 1e-9);
+    }
+
+    #[test]
+    fn it_keeps_characters_that_span_tokens_together() {
+        let transcript = Transcript::new(&tokenizer(), &[(5, -0.1), (6, -0.2), (7, -0.3)]).unwrap();
+        assert_eq!(transcript.text, " café");
+        assert_eq!(transcript.tokens.len(), 2);
+        assert_eq!(transcript.tokens[1].text, "é");
+        assert!((transcript.tokens[1].logprob - -0.5).abs() < 1e-9);
+        assert_eq!(transcript.words.len(), 1);
+        assert_eq!(transcript.words[0].word, "café");
+    }
+
+    #[test]
+    fn it_can_render_text_or_json() {
+        let transcript = Transcript::new(&tokenizer(), &[(0, 0.0)]).unwrap();
+        assert_eq!(ResponseFormat::Text.render(&transcript), " The");
+        let json: serde_json::Value =
+            serde_json::from_str(&ResponseFormat::Json.render(&transcript)).unwrap();
+        assert_eq!(json["outcome"], "recognized");
+        assert_eq!(json["text"], " The");
+        assert_eq!(json["words"][0]["word"], "The");
+        assert_eq!(json["words"][0]["confidence"], 1.0);
+        assert_eq!(json["tokens"][0]["logprob"], 0.0);
+    }
+
+    #[test]
+    fn it_can_render_subtitles() {
+        let transcript = Transcript {
+            segments: vec![
+                Segment {
+                    start: 0.0,
+                    end: 30.0,
+                    text: " The Complete Book of Cheese".to_owned(),
+                },
+                Segment {
+                    start: 30.0,
+                    end: 3725.25,
+                    text: " by Robert Carlton Brown".to_owned(),
+                },
+            ],
+            ..Default::default()
+        };
+        assert_eq!(
+            ResponseFormat::Srt.render(&transcript),
+            "1\n00:00:00,000 --> 00:00:30,000\nThe Complete Book of Cheese\n\n\
+             2\n00:00:30,000 --> 01:02:05,250\nby Robert Carlton Brown\n\n"
+        );
+        assert_eq!(
+            ResponseFormat::Vtt.render(&transcript),
+            "WEBVTT\n\n\
+             00:00:00.000 --> 00:00:30.000\nThe Complete Book of Cheese\n\n\
+             00:00:30.000 --> 01:02:05.250\nby Robert Carlton Brown\n\n"
+        );
+    }
+
+    #[test]
+    fn it_classifies_the_outcome() {
+        let tokenizer = tokenizer();
+        let confident = Transcript::new(&tokenizer, &[(0, -0.1), (1, -0.1)]).unwrap();
+        let unsure = Transcript::new(&tokenizer, &[(0, -0.1), (1, -3.0)]).unwrap();
+        let empty = Transcript::new(&tokenizer, &[]).unwrap();
+        assert_eq!(
+            Outcome::classify(&confident, false, 0.5),
+            Outcome::Recognized
+        );
+        assert_eq!(
+            Outcome::classify(&unsure, false, 0.5),
+            Outcome::LowConfidence
+        );
+        assert_eq!(Outcome::classify(&unsure, false, 0.1), Outcome::Recognized);
+        assert_eq!(Outcome::classify(&empty, false, 0.5), Outcome::NoSpeech);
+        assert_eq!(Outcome::classify(&empty, true, 0.5), Outcome::LowConfidence);
+    }
+
+    #[test]
+    fn it_blames_noise_only_when_it_could_not_recognize_anything() {
+        let mut transcript = Transcript {
+            outcome: Outcome::NoSpeech,
+            ..Default::default()
+        };
+        transcript.check_noise(20.0, 6.0);
+        assert_eq!(transcript.outcome, Outcome::NoSpeech);
+        transcript.check_noise(3.0, 6.0);
+        assert_eq!(transcript.outcome, Outcome::TooNoisy);
+
+        let mut transcript = Transcript::default();
+        transcript.check_noise(3.0, 6.0);
+        assert_eq!(transcript.outcome, Outcome::Recognized);
+    }
+
+    #[test]
+    fn it_handles_an_empty_transcript() {
+        assert_eq!(
+            Transcript::new(&tokenizer(), &[]).unwrap(),
+            Transcript::default()
+        );
+    }
+
+    #[test]
+    fn it_tells_text_clients_when_it_did_not_recognize_anything() {
+        let mut transcript = Transcript {
+            text: " ...".to_owned(),
+            ..Default::default()
+        };
+        assert_eq!(ResponseFormat::Text.render(&transcript), " ...");
+        transcript.outcome = Outcome::NoSpeech;
+        assert_eq!(
+            ResponseFormat::Text.render(&transcript),
+            r#"{"outcome":"no_speech"}"#
+        );
+    }
+}
@@ -1,23 +1,32 @@
 // This module is responsible for transcribing!
 
-use crate::{config, whisper_repo::WhisperRepo};
+use crate::{
+    config,
+    decoding_options::DecodingOptions,
+    grammar::{ConstrainedVocabulary, Grammar},
+    hotwords::{self, Trie},
+    transcript::{self, Outcome, Transcript},
+    whisper_repo::{WeightsFormat, WhisperRepo},
+};
 use anyhow::anyhow;
 use candle_core::{
     Device, IndexOp, Tensor,
     utils::{cuda_is_available, metal_is_available},
 };
-use candle_nn::ops::softmax;
+use candle_nn::{VarBuilder, ops::softmax};
 use candle_transformers::models::whisper::{
-    COMPRESSION_RATIO_THRESHOLD, EOT_TOKEN, HOP_LENGTH, LOGPROB_THRESHOLD, N_FRAMES,
-    NO_SPEECH_THRESHOLD, NO_SPEECH_TOKENS, NO_TIMESTAMPS_TOKEN, SAMPLE_RATE, SOT_TOKEN,
-    TEMPERATURES, TRANSCRIBE_TOKEN,
-    quantized_model::{self, Whisper},
+    Config, DTYPE, EOT_TOKEN, HOP_LENGTH, N_FRAMES, NO_SPEECH_TOKENS, NO_TIMESTAMPS_TOKEN,
+    SAMPLE_RATE, SOT_TOKEN, TRANSCRIBE_TOKEN, model, quantized_model,
 };
-use futures::channel::mpsc::Sender;
+use flate2::{Compression, write::ZlibEncoder};
 use rand::distr::weighted::WeightedIndex;
 use rand::{SeedableRng, distr::Distribution};
+use std::io::Write;
 use tokenizers::Tokenizer;
 
+// Marks the start of a text prompt, see https://github.com/openai/whisper/discussions/117
+const START_OF_PREVIOUS_TOKEN: &str = "<|startofprev|>";
+
 fn device() -> Device {
     if metal_is_available() {
         Device::new_metal(0).unwrap()
@@ -30,46 +39,126 @@ fn device() -> Device {
 
 pub fn transcribe(
     features: Vec<f32>,
-    sender: &mut Sender<String>,
-) -> Result<String, anyhow::Error> {
+    repo: &WhisperRepo,
+    options: &DecodingOptions,
+) -> Result<Transcript, anyhow::Error> {
+    options.validate()?;
     let mel_len = features.len();
     let device = device();
     let mel = Tensor::from_vec(
         features,
         (
             1,
-            WhisperRepo::get().config().num_mel_bins,
-            mel_len / WhisperRepo::get().config().num_mel_bins,
+            repo.config().num_mel_bins,
+            mel_len / repo.config().num_mel_bins,
         ),
         &device,
     )?;
 
-    let vb = candle_transformers::quantized_var_builder::VarBuilder::from_gguf(
-        &WhisperRepo::get().weights_file,
-        &device,
-    )?;
-    let model = quantized_model::Whisper::load(&vb, WhisperRepo::get().config())?;
+    let model = Model::load(repo, &device)?;
 
+    let tokenizer = repo.tokenizer();
+    let hotwords = Trie::from_phrases(&tokenizer, &hotwords::phrases())?;
     let mut dc = Decoder::new(
         model,
-        WhisperRepo::get().tokenizer(),
+        tokenizer,
         &device,
         None, // TODO: optionally pass in a language token
+        options.clone(),
+        hotwords,
     )?;
     let segments = dc.run(&mel)?;
-    let all = segments
+    let text_tokens: Vec<(u32, f64)> = segments
+        .iter()
+        .flat_map(|s| s.dr.text_tokens.iter().copied())
+        .collect();
+    let mut transcript = Transcript::new(&dc.tokenizer, &text_tokens)?;
+    // The features were extracted as if the samples were at whisper's sample rate,
+    // so we scale the segment times back to the recording's time
+    let time_scale = SAMPLE_RATE as f64 / config::AUDIO_DECODE_SAMPLE_RATE as f64;
+    transcript.segments = segments
         .iter()
-        .map(|s| s.transcription())
-        .collect::<String>();
-    sender.try_send(all.clone()).unwrap();
-    Ok(all)
+        .map(|s| transcript::Segment {
+            start: s.start * time_scale,
+            end: (s.start + s.duration) * time_scale,
+            text: s.dr.text.clone(),
+        })
+        .collect();
+    transcript.outcome = Outcome::classify(
+        &transcript,
+        dc.hallucinated_segments > 0,
+        options.min_confidence,
+    );
+    Ok(transcript)
+}
+
+// Either a quantized or a full precision whisper model, depending on the repo's weights file
+enum Model {
+    Quantized(quantized_model::Whisper),
+    Normal(model::Whisper),
+}
+
+impl Model {
+    fn load(repo: &WhisperRepo, device: &Device) -> Result<Model, anyhow::Error> {
+        match repo.weights_format()? {
+            WeightsFormat::Gguf => {
+                let vb = candle_transformers::quantized_var_builder::VarBuilder::from_gguf(
+                    &repo.weights_file,
+                    device,
+                )?;
+                Ok(Model::Quantized(quantized_model::Whisper::load(
+                    &vb,
+                    repo.config(),
+                )?))
+            }
+            WeightsFormat::Safetensors => {
+                let vb = unsafe {
+                    VarBuilder::from_mmaped_safetensors(&[&repo.weights_file], DTYPE, device)?
+                };
+                Ok(Model::Normal(model::Whisper::load(&vb, repo.config())?))
+            }
+        }
+    }
+
+    fn config(&self) -> &Config {
+        match self {
+            Model::Quantized(m) => &m.config,
+            Model::Normal(m) => &m.config,
+        }
+    }
+
+    fn encoder_forward(&mut self, x: &Tensor, flush: bool) -> candle_core::Result<Tensor> {
+        match self {
+            Model::Quantized(m) => m.encoder.forward(x, flush),
+            Model::Normal(m) => m.encoder.forward(x, flush),
+        }
+    }
+
+    fn decoder_forward(
+        &mut self,
+        x: &Tensor,
+        xa: &Tensor,
+        flush: bool,
+    ) -> candle_core::Result<Tensor> {
+        match self {
+            Model::Quantized(m) => m.decoder.forward(x, xa, flush),
+            Model::Normal(m) => m.decoder.forward(x, xa, flush),
+        }
+    }
+
+    fn decoder_final_linear(&self, x: &Tensor) -> candle_core::Result<Tensor> {
+        match self {
+            Model::Quantized(m) => m.decoder.final_linear(x),
+            Model::Normal(m) => m.decoder.final_linear(x),
+        }
+    }
 }
 
 // The following is all copy/pasted from https://github.com/vberthet/candle/blob/rocm/candle-examples/examples/whisper/main.rs
 // A lot can be re-written and/or simplified
 
 struct Decoder {
-    model: Whisper,
+    model: Model,
     rng: rand::rngs::StdRng,
     tokenizer: Tokenizer,
     suppress_tokens: Tensor,
@@ -79,21 +168,45 @@ struct Decoder {
     no_speech_token: u32,
     no_timestamps_token: u32,
     language_token: Option<u32>,
+    options: DecodingOptions,
+    sample_len: usize,
+    // Tokens of the text prompt, already prefixed with <|startofprev|>
+    prompt_tokens: Vec<u32>,
+    hotwords: Trie,
+    // When present, only transcriptions that match the grammar are allowed
+    grammar: Option<ConstrainedVocabulary>,
+    // How many segments were thrown out because they looked like hallucinations
+    hallucinated_segments: usize,
 }
 
 impl Decoder {
     fn new(
-        model: Whisper,
+        model: Model,
         tokenizer: Tokenizer,
         device: &Device,
         language_token: Option<u32>,
+        options: DecodingOptions,
+        hotwords: Trie,
     ) -> Result<Self, anyhow::Error> {
         let no_timestamps_token = token_id(&tokenizer, NO_TIMESTAMPS_TOKEN)?;
+        if let Some(token) = options
+            .suppress_tokens
+            .iter()
+            .find(|&&token| token as usize >= model.config().vocab_size)
+        {
+            anyhow::bail!(
+                "suppress_tokens must be less than the vocabulary size {}, but got {token}",
+                model.config().vocab_size
+            );
+        }
+        let sample_len = options.sample_len(model.config().max_target_positions)?;
         // Suppress the notimestamps token when in timestamps mode.
         // https://github.com/openai/whisper/blob/e8622f9afc4eba139bf796c210f5c01081000472/whisper/decoding.py#L452
-        let suppress_tokens: Vec<f32> = (0..model.config.vocab_size as u32)
+        let suppress_tokens: Vec<f32> = (0..model.config().vocab_size as u32)
             .map(|i| {
-                if model.config.suppress_tokens.contains(&i) {
+                if model.config().suppress_tokens.contains(&i)
+                    || options.suppress_tokens.contains(&i)
+                {
                     f32::NEG_INFINITY
                 } else {
                     0f32
@@ -111,9 +224,22 @@ impl Decoder {
             None => anyhow::bail!("unable to find any non-speech token"),
             Some(n) => n,
         };
+        let prompt_tokens = match &options.prompt {
+            Some(prompt) => prompt_tokens(&tokenizer, prompt, model.config())?,
+            None => vec![],
+        };
+        let grammar = match &options.grammar {
+            Some(pattern) => Some(ConstrainedVocabulary::new(
+                Grammar::new(pattern)?,
+                &tokenizer,
+                model.config().vocab_size,
+                eot_token,
+            )?),
+            None => None,
+        };
         Ok(Self {
             model,
-            rng: rand::rngs::StdRng::seed_from_u64(config::SEED),
+            rng: rand::rngs::StdRng::seed_from_u64(options.seed),
             tokenizer,
             suppress_tokens,
             sot_token,
@@ -122,33 +248,53 @@ impl Decoder {
             no_speech_token,
             language_token,
             no_timestamps_token,
+            options,
+            sample_len,
+            prompt_tokens,
+            hotwords,
+            grammar,
+            hallucinated_segments: 0,
         })
     }
 
     fn decode(&mut self, mel: &Tensor, t: f64) -> Result<DecodingResult, anyhow::Error> {
         let model = &mut self.model;
-        let audio_features = model.encoder.forward(mel, true)?;
-        let sample_len = model.config.max_target_positions / 2;
+        let audio_features = model.encoder_forward(mel, true)?;
+        let sample_len = self.sample_len;
         let mut sum_logprob = 0f64;
+        // Each token that the model chose, and the log of its probability
+        let mut text_tokens = vec![];
         let mut no_speech_prob = f64::NAN;
-        let mut tokens = vec![self.sot_token];
+        // Everything before the SOT token is context for the model, not part of the transcription
+        let sot_index = self.prompt_tokens.len();
+        let mut tokens = self.prompt_tokens.clone();
+        tokens.push(self.sot_token);
         if let Some(language_token) = self.language_token {
             tokens.push(language_token);
         }
-        tokens.push(self.transcribe_token);
+        // English-only models don't know about tasks like transcribe or translate
+        if is_multilingual(model.config()) {
+            tokens.push(self.transcribe_token);
+        }
         tokens.push(self.no_timestamps_token);
+        let sample_begin = tokens.len();
+        // Where the grammar has got to with the tokens chosen so far
+        let mut grammar_position = self.grammar.as_ref().map(ConstrainedVocabulary::start);
         for i in 0..sample_len {
             let tokens_t = Tensor::new(tokens.as_slice(), mel.device())?;
 
             // The model expects a batch dim but this inference loop does not handle
             // it so we add it at this point.
             let tokens_t = tokens_t.unsqueeze(0)?;
-            let ys = model.decoder.forward(&tokens_t, &audio_features, i == 0)?;
+            let ys = model.decoder_forward(&tokens_t, &audio_features, i == 0)?;
 
-            // Extract the no speech probability on the first iteration by looking at the first
+            // Extract the no speech probability on the first iteration by looking at the SOT
             // token logits and the probability for the according token.
             if i == 0 {
-                let logits = model.decoder.final_linear(&ys.i(..1)?)?.i(0)?.i(0)?;
+                let logits = model
+                    .decoder_final_linear(&ys.i(..1)?)?
+                    .i(0)?
+                    .i(sot_index)?;
                 no_speech_prob = softmax(&logits, 0)?
                     .i(self.no_speech_token as usize)?
                     .to_scalar::<f32>()? as f64;
@@ -156,12 +302,24 @@ impl Decoder {
 
             let (_, seq_len, _) = ys.dims3()?;
             let logits = model
-                .decoder
-                .final_linear(&ys.i((..1, seq_len - 1..))?)?
+                .decoder_final_linear(&ys.i((..1, seq_len - 1..))?)?
                 .i(0)?
                 .i(0)?;
 
-            let logits = logits.broadcast_add(&self.suppress_tokens)?;
+            let mut logits = logits.broadcast_add(&self.suppress_tokens)?;
+            if !self.hotwords.is_empty() {
+                let bonus = hotword_bonus(
+                    &self.hotwords,
+                    &tokens[sample_begin..],
+                    model.config().vocab_size,
+                    mel.device(),
+                )?;
+                logits = logits.broadcast_add(&bonus)?;
+            }
+            if let (Some(grammar), Some(position)) = (&mut self.grammar, &grammar_position) {
+                let mask = Tensor::new(grammar.mask(position), mel.device())?;
+                logits = logits.broadcast_add(&mask)?;
+            }
             let next_token = if t > 0f64 {
                 let prs = softmax(&(&logits / t)?, 0)?;
                 let logits_v: Vec<f32> = prs.to_vec1()?;
@@ -180,39 +338,52 @@ impl Decoder {
             let prob = softmax(&logits, candle_core::shape::D::Minus1)?
                 .i(next_token as usize)?
                 .to_scalar::<f32>()? as f64;
-            if next_token == self.eot_token || tokens.len() > model.config.max_target_positions {
+            if next_token == self.eot_token || tokens.len() > model.config().max_target_positions {
                 break;
             }
             sum_logprob += prob.ln();
+            text_tokens.push((next_token, prob.ln()));
+            if let (Some(grammar), Some(position)) = (&self.grammar, &mut grammar_position) {
+                *position = grammar.advance(position, next_token);
+            }
         }
+        let tokens = tokens.split_off(sot_index);
         let text = self
             .tokenizer
             .decode(&tokens, true)
             .map_err(anyhow::Error::msg)?;
         let avg_logprob = sum_logprob / tokens.len() as f64;
+        let compression_ratio = compression_ratio(&text)?;
+        let repetition_loop = has_repetition_loop(&text);
 
         Ok(DecodingResult {
             tokens,
+            text_tokens,
             text,
             avg_logprob,
             no_speech_prob,
             temperature: t,
-            compression_ratio: f64::NAN,
+            compression_ratio,
+            repetition_loop,
         })
     }
 
     fn decode_with_fallback(&mut self, segment: &Tensor) -> Result<DecodingResult, anyhow::Error> {
-        for (i, &t) in TEMPERATURES.iter().enumerate() {
+        let temperatures = self.options.temperatures.clone();
+        for (i, &t) in temperatures.iter().enumerate() {
             let dr: Result<DecodingResult, anyhow::Error> = self.decode(segment, t);
-            if i == TEMPERATURES.len() - 1 {
+            if i == temperatures.len() - 1 {
                 return dr;
             }
             // On errors, we try again with a different temperature.
             match dr {
                 Ok(dr) => {
-                    let needs_fallback = dr.compression_ratio > COMPRESSION_RATIO_THRESHOLD
-                        || dr.avg_logprob < LOGPROB_THRESHOLD;
-                    if !needs_fallback || dr.no_speech_prob > NO_SPEECH_THRESHOLD {
+                    let needs_fallback = dr.looks_like_hallucination(&self.options)
+                        || dr.avg_logprob < self.options.logprob_threshold;
+                    if !needs_fallback
+                        || (dr.no_speech_prob > self.options.no_speech_threshold
+                            && !dr.repetition_loop)
+                    {
                         return Ok(dr);
                     }
                 }
@@ -235,7 +406,18 @@ impl Decoder {
             let segment_duration = (segment_size * HOP_LENGTH) as f64 / SAMPLE_RATE as f64;
             let dr = self.decode_with_fallback(&mel_segment)?;
             seek += segment_size;
-            if dr.no_speech_prob > NO_SPEECH_THRESHOLD && dr.avg_logprob < LOGPROB_THRESHOLD {
+            if dr.no_speech_prob > self.options.no_speech_threshold
+                && dr.avg_logprob < self.options.logprob_threshold
+            {
+                continue;
+            }
+            // Even the highest temperature could not get us out of a loop
+            if dr.looks_like_hallucination(&self.options) {
+                log::warn!(
+                    "Discarding a segment that looks like a hallucination: {:?}",
+                    dr.text
+                );
+                self.hallucinated_segments += 1;
                 continue;
             }
             let segment = Segment {
@@ -249,6 +431,88 @@ impl Decoder {
     }
 }
 
+// Add config::HOTWORD_BOOST to the logits of any token that continues a hotword
+fn hotword_bonus(
+    hotwords: &Trie,
+    hypothesis: &[u32],
+    vocab_size: usize,
+    device: &Device,
+) -> Result<Tensor, anyhow::Error> {
+    let mut bonus = vec![0f32; vocab_size];
+    for token in hotwords.continuations(hypothesis) {
+        if let Some(b) = bonus.get_mut(token as usize) {
+            *b = config::HOTWORD_BOOST;
+        }
+    }
+    Ok(Tensor::new(bonus.as_slice(), device)?)
+}
+
+// Turn a text prompt into the <|startofprev|> context that whisper expects before the SOT token.
+// Like the python implementation, we only keep the last half of the context window.
+// See: https://github.com/openai/whisper/blob/e8622f9afc4eba139bf796c210f5c01081000472/whisper/decoding.py#L599
+fn prompt_tokens(
+    tokenizer: &Tokenizer,
+    prompt: &str,
+    config: &Config,
+) -> Result<Vec<u32>, anyhow::Error> {
+    let prompt = prompt.trim();
+    if prompt.is_empty() {
+        return Ok(vec![]);
+    }
+    let encoding = tokenizer
+        .encode(format!(" {prompt}"), false)
+        .map_err(anyhow::Error::msg)?;
+    let mut tokens = vec![token_id(tokenizer, START_OF_PREVIOUS_TOKEN)?];
+    tokens.extend(last_n(
+        encoding.get_ids(),
+        config.max_target_positions / 2 - 1,
+    ));
+    Ok(tokens)
+}
+
+fn last_n(tokens: &[u32], n: usize) -> &[u32] {
+    &tokens[tokens.len().saturating_sub(n)..]
+}
+
+// How well the text compresses.  Repetitive text, like the loops that whisper
+// sometimes gets stuck in, compresses very well.  This uses zlib, like the python
+// implementation: https://github.com/openai/whisper/blob/e8622f9afc4eba139bf796c210f5c01081000472/whisper/utils.py#L45
+fn compression_ratio(text: &str) -> Result<f64, anyhow::Error> {
+    if text.is_empty() {
+        return Ok(0.0);
+    }
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(text.as_bytes())?;
+    let compressed = encoder.finish()?;
+    Ok(text.len() as f64 / compressed.len() as f64)
+}
+
+// Whether the same word or phrase appears more than config::MAX_CONSECUTIVE_REPEATS
+// times in a row (e.g. "by Paul by Paul by Paul"), or the text is nothing but the
+// same punctuation over and over (e.g. "...").  Short queries do not compress
+// well enough for the compression ratio to catch these.
+fn has_repetition_loop(text: &str) -> bool {
+    if !text.chars().any(char::is_alphanumeric) {
+        let marks: Vec<char> = text.chars().filter(|c| !c.is_whitespace()).collect();
+        return marks
+            .windows(config::MAX_CONSECUTIVE_REPEATS + 1)
+            .any(|run| run.iter().all(|c| *c == run[0]));
+    }
+    let words: Vec<String> = text.split_whitespace().map(str::to_lowercase).collect();
+    (1..=config::MAX_REPEATED_PHRASE_LENGTH).any(|n| {
+        // The phrase, and then each of its repeats
+        let span = n * (config::MAX_CONSECUTIVE_REPEATS + 1);
+        (0..(words.len() + 1).saturating_sub(span))
+            .any(|start| (start + n..start + span).all(|i| words[i] == words[i - n]))
+    })
+}
+
+// The same rule as the original whisper implementation, see
+// https://github.com/openai/whisper/blob/main/whisper/model.py
+fn is_multilingual(config: &Config) -> bool {
+    config.vocab_size >= 51865
+}
+
 pub fn token_id(tokenizer: &Tokenizer, token: &str) -> Result<u32, anyhow::Error> {
     match tokenizer.token_to_id(token) {
         None => Err(anyhow!("no token-id for {token}")),
@@ -260,11 +524,19 @@ pub fn token_id(tokenizer: &Tokenizer, token: &str) -> Result<u32, anyhow::Error
 #[derive(Debug, Clone)]
 struct DecodingResult {
     tokens: Vec<u32>,
+    text_tokens: Vec<(u32, f64)>,
     text: String,
     avg_logprob: f64,
     no_speech_prob: f64,
     temperature: f64,
     compression_ratio: f64,
+    repetition_loop: bool,
+}
+
+impl DecodingResult {
+    fn looks_like_hallucination(&self, options: &DecodingOptions) -> bool {
+        self.compression_ratio > options.compression_ratio_threshold || self.repetition_loop
+    }
 }
 
 #[allow(dead_code)]
@@ -275,27 +547,113 @@ struct Segment {
     dr: DecodingResult,
 }
 
-impl Segment {
-    pub fn transcription(&self) -> String {
-        self.dr.text.clone()
-    }
-}
-
 #[cfg(test)]
 mod tests {
-    use futures::channel::mpsc::channel;
 
     use super::*;
-    use crate::{audio, feature_extraction::extract_features};
+    use crate::{audio, feature_extraction::extract_features, model_registry::ModelRegistry};
+    use candle_transformers::models::whisper::COMPRESSION_RATIO_THRESHOLD;
     use std::fs::File;
 
     fn transcribe_file(path: &str) -> String {
+        transcribe_file_with_options(path, DecodingOptions::default())
+    }
+
+    fn transcribe_file_with_options(path: &str, options: DecodingOptions) -> String {
         let file = File::open(path).unwrap();
-        let (samples, _) = audio::pcm_decode(file).unwrap();
-        let features = extract_features(samples).unwrap();
-        let (mut sender, mut receiver) = channel(5);
-        let _ = transcribe(features, &mut sender);
-        receiver.try_next().unwrap().unwrap().to_lowercase()
+        let (samples, _) =
+            audio::tests::pcm_decode(file, audio::ChannelSelection::default()).unwrap();
+        transcribe_samples(samples, options)
+    }
+
+    fn transcribe_samples(samples: Vec<f32>, options: DecodingOptions) -> String {
+        let registry = ModelRegistry::get().unwrap();
+        let repo = registry.default_model().try_repo().unwrap();
+        let features = extract_features(samples, repo).unwrap();
+        transcribe(features, repo, &options)
+            .unwrap()
+            .text
+            .to_lowercase()
+    }
+
+    #[test]
+    fn it_is_confident_about_clearly_spoken_words() {
+        let file = File::open("./test_data/english/complete_book_of_cheese_mono.webm").unwrap();
+        let (samples, _) =
+            audio::tests::pcm_decode(file, audio::ChannelSelection::default()).unwrap();
+        let registry = ModelRegistry::get().unwrap();
+        let repo = registry.default_model().try_repo().unwrap();
+        let features = extract_features(samples, repo).unwrap();
+        let transcript = transcribe(features, repo, &DecodingOptions::default()).unwrap();
+        let cheese = transcript
+            .words
+            .iter()
+            .find(|w| w.word.to_lowercase() == "cheese")
+            .unwrap();
+        assert!(cheese.confidence > 0.5);
+        assert!(transcript.tokens.iter().all(|t| t.logprob <= 0.0));
+    }
+
+    #[test]
+    fn it_keeps_the_end_of_a_long_prompt() {
+        assert_eq!(last_n(&[1, 2, 3, 4, 5], 2), &[4, 5]);
+        assert_eq!(last_n(&[1, 2], 5), &[1, 2]);
+    }
+
+    #[test]
+    fn it_calculates_a_high_compression_ratio_for_repetitive_text() {
+        let repetitive = " Thank you.".repeat(20);
+        assert!(compression_ratio(&repetitive).unwrap() > COMPRESSION_RATIO_THRESHOLD);
+        let normal = " The Complete Book of Cheese by Robert Carlton Brown";
+        assert!(compression_ratio(normal).unwrap() < COMPRESSION_RATIO_THRESHOLD);
+        assert_eq!(compression_ratio("").unwrap(), 0.0);
+    }
+
+    #[test]
+    fn it_detects_repetition_loops() {
+        assert!(has_repetition_loop(" ... ... ..."));
+        assert!(has_repetition_loop(" ..."));
+        assert!(has_repetition_loop(" the the the the"));
+        assert!(has_repetition_loop(" by Paul by Paul by Paul"));
+        assert!(!has_repetition_loop(" New York, New York"));
+        assert!(!has_repetition_loop(
+            " The Complete Book of Cheese by Robert Carlton Brown"
+        ));
+        assert!(!has_repetition_loop(""));
+        // Two and a half repeats of a phrase is not a loop, but three is
+        assert!(!has_repetition_loop(" a b a b a"));
+        assert!(has_repetition_loop(" a b a b a b"));
+        assert!(!has_repetition_loop(" to be or not to be or not"));
+        assert!(has_repetition_loop(" he said yes he said yes he said yes"));
+        // Punctuation on its own, unless it repeats
+        assert!(!has_repetition_loop(" ?"));
+        assert!(!has_repetition_loop(" ?!"));
+        assert!(has_repetition_loop(" ?!!!"));
+    }
+
+    #[test]
+    fn it_does_not_include_the_prompt_in_the_transcription() {
+        let transcription = transcribe_file_with_options(
+            "./test_data/english/complete_book_of_cheese_mono.webm",
+            DecodingOptions {
+                prompt: Some("Princeton University Library catalog, author, title".to_owned()),
+                ..Default::default()
+            },
+        );
+        assert!(transcription.contains("the complete book of cheese"));
+        assert!(!transcription.contains("princeton"));
+    }
+
+    #[test]
+    fn it_can_constrain_the_transcription_to_a_grammar() {
+        let transcription = transcribe_file_with_options(
+            "./test_data/english/complete_book_of_cheese_mono.webm",
+            DecodingOptions {
+                grammar: Some("the complete book of (cheese|wine)".to_owned()),
+                ..Default::default()
+            },
+        );
+        assert_eq!(transcription.trim(), "the complete book of cheese");
     }
 
     #[test]
@@ -389,4 +747,91 @@ mod tests {
         assert!(transcription.contains("владимира жаботинского"));
         assert!(transcription.contains("первый вариант перевода"));
     }
+
+    // Not a regular test, since it prints a report rather than checking anything.
+    // It compares resampling to whisper's 16 kHz with the old workaround of
+    // handing 12 kHz samples to whisper as if they were 16 kHz, see config.rs.
+    #[test]
+    #[ignore]
+    fn compare_sample_rates() {
+        // Phrases that we would like to find in each transcription, including the
+        // ones that the tests above have given up on
+        let expected = [
+            (
+                "./test_data/english/alexander_the_great_mono.webm",
+                vec!["an alphabet of history", "wilbur d. nesbit", "alexander"],
+            ),
+            (
+                "./test_data/english/lifes_tragedy_mono.webm",
+                vec!["life's tragedy", "by paul laurence dunbar"],
+            ),
+            (
+                "./test_data/english/complete_book_of_cheese_mono.webm",
+                vec!["the complete book of cheese", "by robert carlton brown"],
+            ),
+            (
+                "./test_data/english/long_arm_mono.webm",
+                vec!["the long arm", "by richard harding davis"],
+            ),
+            (
+                "./test_data/portuguese/semana_de_arte_moderna_mono.webm",
+                vec![
+                    "sessão 2",
+                    "semana de arte moderna de 1922",
+                    "coletânea centenário",
+                ],
+            ),
+            (
+                "./test_data/portuguese/a_filha_do_patrao_stereo.webm",
+                vec!["a filha do patrão", "artur de azevedo"],
+            ),
+            (
+                "./test_data/russian/po_nedele_ni_slova_ni_s_kem_ne_skazhu_mono.webm",
+                vec!["по неделе ни слова ни с кем не скажу"],
+            ),
+            (
+                "./test_data/russian/vseobshchaia_deklaratsiia_prav_cheloveka.webm",
+                vec![
+                    "всеобщая декларация прав человека",
+                    "принята и провозглашена резолюцией 217а",
+                    "генеральной ассамблеи от 10 декабря 1948 года",
+                    "преамбула",
+                ],
+            ),
+            (
+                "./test_data/russian/voron_mono_8MHz.webm",
+                vec![
+                    "эдгар аллан по",
+                    "ворон",
+                    "перевод",
+                    "владимира жаботинского",
+                    "первый вариант перевода",
+                ],
+            ),
+        ];
+        let total: usize = expected.iter().map(|(_, phrases)| phrases.len()).sum();
+        for sample_rate in [12_000, 16_000] {
+            let mut found = 0;
+            for (path, phrases) in &expected {
+                let file = File::open(path).unwrap();
+                let (samples, _) = audio::tests::pcm_decode_at(
+                    file,
+                    audio::ChannelSelection::default(),
+                    sample_rate,
+                )
+                .unwrap();
+                let transcription = transcribe_samples(samples, DecodingOptions::default());
+                let matches = phrases
+                    .iter()
+                    .filter(|phrase| transcription.contains(*phrase))
+                    .count();
+                println!(
+                    "{sample_rate} Hz, {matches}/{} phrases, {path}:{transcription}",
+                    phrases.len()
+                );
+                found += matches;
+            }
+            println!("{sample_rate} Hz: found {found} of {total} phrases\n");
+        }
+    }
 }
@@ -1,12 +1,14 @@
-use crate::config;
+use crate::model_registry::ModelSpec;
 use anyhow::Error;
 use candle_transformers::models::whisper::Config;
 use hf_hub::{Repo, RepoType, api::sync::Api};
-use std::{path::PathBuf, sync::OnceLock};
+use std::{
+    fs::File,
+    io::Read,
+    path::{Path, PathBuf},
+};
 use tokenizers::Tokenizer;
 
-pub static WHISPER_REPO: OnceLock<WhisperRepo> = OnceLock::new();
-
 #[derive(Debug, Clone)]
 pub struct WhisperRepo {
     pub config_file: PathBuf,
@@ -14,6 +16,36 @@ pub struct WhisperRepo {
     pub weights_file: PathBuf,
 }
 
+// How the model's weights are stored, which determines how we load them
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WeightsFormat {
+    // Quantized weights, loaded into quantized_model::Whisper
+    Gguf,
+    // Full precision weights, loaded into model::Whisper
+    Safetensors,
+}
+
+impl WeightsFormat {
+    // Inspect the start of the file, since the filename in the repo may not have the usual extension
+    pub fn of(weights_file: &Path) -> Result<WeightsFormat, Error> {
+        let mut magic = [0u8; 4];
+        File::open(weights_file)?.read_exact(&mut magic)?;
+        if &magic == b"GGUF" {
+            return Ok(WeightsFormat::Gguf);
+        }
+        match weights_file
+            .extension()
+            .and_then(|extension| extension.to_str())
+        {
+            Some("gguf") => Ok(WeightsFormat::Gguf),
+            Some("safetensors") => Ok(WeightsFormat::Safetensors),
+            _ => anyhow::bail!(
+                "Could not tell whether {weights_file:?} is a gguf or safetensors file"
+            ),
+        }
+    }
+}
+
 impl WhisperRepo {
     pub fn config(&self) -> Config {
         serde_json::from_str(&std::fs::read_to_string(&self.config_file).unwrap()).unwrap()
@@ -23,21 +55,65 @@ impl WhisperRepo {
         Tokenizer::from_file(&self.tokenizer_file).unwrap()
     }
 
-    pub fn get() -> &'static WhisperRepo {
-        WHISPER_REPO.get_or_init(|| download().unwrap())
+    pub fn weights_format(&self) -> Result<WeightsFormat, Error> {
+        WeightsFormat::of(&self.weights_file)
+    }
+
+    // Download the model's files from the HuggingFace hub, or find them in a local
+    // directory if the spec has a path
+    pub fn download(spec: &ModelSpec) -> Result<WhisperRepo, Error> {
+        if let Some(path) = &spec.path {
+            return Ok(WhisperRepo {
+                config_file: path.join(&spec.config_filename),
+                tokenizer_file: path.join(&spec.tokenizer_filename),
+                weights_file: path.join(&spec.model_filename),
+            });
+        }
+        let api = Api::new()?;
+        let repo = api.repo(Repo::with_revision(
+            spec.repo_id.clone(),
+            RepoType::Model,
+            spec.revision.clone(),
+        ));
+        Ok(WhisperRepo {
+            config_file: repo.get(&spec.config_filename)?,
+            tokenizer_file: repo.get(&spec.tokenizer_filename)?,
+            weights_file: repo.get(&spec.model_filename)?,
+        })
     }
 }
 
-fn download() -> Result<WhisperRepo, Error> {
-    let api = Api::new()?;
-    let repo = api.repo(Repo::with_revision(
-        config::REPO_ID.to_owned(),
-        RepoType::Model,
-        "main".to_owned(),
-    ));
-    Ok(WhisperRepo {
-        config_file: repo.get(config::MODEL_CONFIG_FILENAME).unwrap(),
-        tokenizer_file: repo.get(config::TOKENIZER_FILENAME).unwrap(),
-        weights_file: repo.get(config::MODEL_FILENAME).unwrap(),
-    })
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_recognizes_gguf_files_by_their_contents() {
+        let file = std::env::temp_dir().join("whisper_repo_test_weights.bin");
+        std::fs::write(&file, b"GGUF\x03\x00\x00\x00").unwrap();
+        assert_eq!(WeightsFormat::of(&file).unwrap(), WeightsFormat::Gguf);
+    }
+
+    #[test]
+    fn it_recognizes_safetensors_files_by_their_extension() {
+        let file = std::env::temp_dir().join("whisper_repo_test_weights.safetensors");
+        std::fs::write(&file, b"\x08\x00\x00\x00\x00\x00\x00\x00{}      ").unwrap();
+        assert_eq!(
+            WeightsFormat::of(&file).unwrap(),
+            WeightsFormat::Safetensors
+        );
+    }
+
+    #[test]
+    fn it_errors_on_unknown_weights_files() {
+        let file = std::env::temp_dir().join("whisper_repo_test_weights.pt");
+        std::fs::write(&file, b"not a whisper model").unwrap();
+        assert!(WeightsFormat::of(&file).is_err());
+        let repo = WhisperRepo {
+            config_file: PathBuf::new(),
+            tokenizer_file: PathBuf::new(),
+            weights_file: file,
+        };
+        assert!(repo.weights_format().is_err());
+    }
 }